@@ -0,0 +1,140 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use x8::assembler;
+use x8::error::{Fault, VmError};
+use x8::layout::{MemoryLayout, Permissions, RegionKind};
+use x8::vm::VM;
+
+/*
+Compares VM::step, which executes Decoded instructions from the decode cache,
+with decoding every instruction into a Box<dyn Instruction> before executing
+it, which is what every step did before the cache.
+
+    cargo bench --bench dispatch
+
+With the jit feature the same programs also run through jit::Jit.
+
+    cargo bench --bench dispatch --features jit
+ */
+
+/// Arithmetic and branches only, every instruction decodes the same every time
+const LOOP: &str = "
+    .text
+outer:
+    mov R1, 0
+inner:
+    addi R2, 3
+    xori R2, 0x5a
+    addi R1, 1
+    cmpi R1, 0
+    jne inner
+    addi R0, 1
+    cmpi R0, 0
+    jne outer
+    exit
+";
+
+/// Rewrites the immediate of the next instruction on every iteration, like the
+/// XorMemReg8Const8 decoder in program.bin, so its cache slot keeps being dropped
+const SELF_MODIFYING: &str = "
+    .text
+    mov R3, patch+2
+outer:
+    mov R1, 0
+inner:
+    xorm [R3], 0x01
+patch:
+    addi R2, 0
+    addi R1, 1
+    cmpi R1, 0
+    jne inner
+    addi R0, 1
+    cmpi R0, 0
+    jne outer
+    exit
+";
+
+const RUNS: usize = 5;
+
+fn vm(image: &[u8]) -> VM {
+    let mut layout = MemoryLayout::for_image_len(image.len()).expect("built-in layout");
+    layout.set_permissions(RegionKind::Instructions, Permissions::RWX);
+    let mut vm = VM::new(layout);
+    vm.load(image).expect("image fits the layout");
+    vm
+}
+
+/// One step without the decode cache
+fn step_boxed(vm: &mut VM) -> Result<(), VmError> {
+    let pc = vm.pc();
+    let instruction = vm.fetch()?;
+    vm.set_pc(pc.wrapping_add(instruction.len() as u16));
+    instruction
+        .execute(vm)
+        .map_err(|fault: Fault| VmError::Fault {
+            pc,
+            bytes: instruction.encode(),
+            fault,
+        })
+}
+
+/// Fastest of RUNS runs and the number of instructions a run executes
+fn measure(image: &[u8], step: fn(&mut VM) -> Result<(), VmError>) -> (Duration, u64) {
+    let mut best = Duration::MAX;
+    let mut instructions = 0;
+    for _ in 0..RUNS {
+        let mut vm = vm(image);
+        let start = Instant::now();
+        instructions = 0;
+        while !vm.stop {
+            step(&mut vm).expect("benchmark programs do not fault");
+            instructions += 1;
+        }
+        best = best.min(start.elapsed());
+        black_box(&vm.registers);
+    }
+    (best, instructions)
+}
+
+/// Fastest of RUNS runs through the JIT, with compiling
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+fn measure_jit(image: &[u8]) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let mut vm = vm(image);
+        let mut jit = x8::jit::Jit::new(&vm.layout).expect("executable memory");
+        let start = Instant::now();
+        jit.run(&mut vm).expect("benchmark programs do not fault");
+        best = best.min(start.elapsed());
+        black_box(&vm.registers);
+    }
+    best
+}
+
+fn main() {
+    for (name, source) in [("loop", LOOP), ("self-modifying", SELF_MODIFYING)] {
+        let image = assembler::assemble(source).expect("benchmark programs assemble");
+        let (boxed, instructions) = measure(&image, step_boxed);
+        let (cached, _) = measure(&image, VM::step);
+        let rate = |time: Duration| instructions as f64 / time.as_secs_f64() / 1e6;
+        println!(
+            "{:<16} {} instructions: boxed {:>7.1} M/s, cached {:>7.1} M/s, {:.2}x",
+            name,
+            instructions,
+            rate(boxed),
+            rate(cached),
+            boxed.as_secs_f64() / cached.as_secs_f64()
+        );
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        {
+            let jit = measure_jit(&image);
+            println!(
+                "{:<16} jit {:>7.1} M/s, {:.2}x cached",
+                "",
+                rate(jit),
+                cached.as_secs_f64() / jit.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,728 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use crate::instruction::{Opcode, OperandKind};
+use crate::layout::{MemoryLayout, RegionKind};
+use crate::registers::RegisterIndex;
+
+/*
+Syntax, one statement per line, `;` starts a comment:
+
+    .equ FLAG_LEN, 40
+    .text
+start:
+    mov R0, FLAG_LEN
+    mov R1, hi(table)
+    deref [R1:R2], R4
+    jne start
+    .data
+table:
+    .db 0x41, 'A', "text\n"
+    .zero 16
+
+Sections map onto the regions of a MemoryLayout, MemoryLayout::narrow unless
+the source starts with `.wide` for MemoryLayout::wide or `.layout text, data,
+stack` with the three region sizes. With a 16-bit PC, 8-bit jump targets must
+be in the page of the next instruction. Operands are written in encoding order, as listed
+by Opcode::operands. Labels may be used before they are defined, `.equ` and
+`.zero` values only see symbols defined above them.
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Section {
+    Text,
+    Data,
+    Stack,
+}
+
+impl Section {
+    pub fn kind(&self) -> RegionKind {
+        match self {
+            Section::Text => RegionKind::Instructions,
+            Section::Data => RegionKind::Memory,
+            Section::Stack => RegionKind::Stack,
+        }
+    }
+
+    pub fn range(&self, layout: &MemoryLayout) -> Range<usize> {
+        layout.region(self.kind()).range()
+    }
+
+    pub fn directive(&self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+            Section::Stack => ".stack",
+        }
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new();
+    for (index, line) in source.lines().enumerate() {
+        assembler.line(index + 1, line)?;
+    }
+    assembler.emit()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Directive(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Comma,
+    Colon,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+fn error(line: usize, column: usize, message: impl Into<String>) -> AsmError {
+    AsmError {
+        line,
+        column,
+        message: message.into(),
+    }
+}
+
+fn tokenize(line_number: usize, line: &str) -> Result<Vec<Token>, AsmError> {
+    let chars = line.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut index = 0;
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    while index < chars.len() {
+        let c = chars[index];
+        let column = index + 1;
+        let error = |message: &str| error(line_number, column, message);
+        let punctuation = match c {
+            ',' => Some(TokenKind::Comma),
+            ':' => Some(TokenKind::Colon),
+            '[' => Some(TokenKind::LBracket),
+            ']' => Some(TokenKind::RBracket),
+            '(' => Some(TokenKind::LParen),
+            ')' => Some(TokenKind::RParen),
+            '+' => Some(TokenKind::Plus),
+            '-' => Some(TokenKind::Minus),
+            _ => None,
+        };
+        if let Some(kind) = punctuation {
+            tokens.push(Token { kind, column });
+            index += 1;
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+        if c == '.' || is_ident(c) {
+            let start = index;
+            index += 1;
+            while index < chars.len() && is_ident(chars[index]) {
+                index += 1;
+            }
+            let word = chars[start..index].iter().collect::<String>();
+            let kind = if c == '.' {
+                TokenKind::Directive(word[1..].to_ascii_lowercase())
+            } else if c.is_ascii_digit() {
+                TokenKind::Number(parse_number(&word).ok_or_else(|| error("invalid number"))?)
+            } else {
+                TokenKind::Ident(word)
+            };
+            tokens.push(Token { kind, column });
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            let mut bytes = vec![];
+            index += 1;
+            loop {
+                let Some(&current) = chars.get(index) else {
+                    return Err(error("unterminated literal"));
+                };
+                index += 1;
+                if current == c {
+                    break;
+                }
+                if current != '\\' {
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(current.encode_utf8(&mut buffer).as_bytes());
+                    continue;
+                }
+                let escape = *chars
+                    .get(index)
+                    .ok_or_else(|| error("unterminated literal"))?;
+                index += 1;
+                bytes.push(match escape {
+                    'n' => b'\n',
+                    'r' => b'\r',
+                    't' => b'\t',
+                    '0' => 0,
+                    '\\' | '\'' | '"' => escape as u8,
+                    'x' => {
+                        let digits = chars
+                            .get(index..index + 2)
+                            .ok_or_else(|| error("invalid escape"))?
+                            .iter()
+                            .collect::<String>();
+                        index += 2;
+                        u8::from_str_radix(&digits, 16).map_err(|_| error("invalid escape"))?
+                    }
+                    _ => return Err(error("invalid escape")),
+                });
+            }
+            let kind = if c == '"' {
+                TokenKind::Str(bytes)
+            } else if bytes.len() == 1 {
+                TokenKind::Number(bytes[0] as i64)
+            } else {
+                return Err(error("character literal must be a single byte"));
+            };
+            tokens.push(Token { kind, column });
+            continue;
+        }
+        return Err(error(&format!("unexpected character '{}'", c)));
+    }
+    Ok(tokens)
+}
+
+pub fn parse_number(word: &str) -> Option<i64> {
+    let word = word.replace('_', "");
+    let lower = word.to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_prefix("0o") {
+        (digits, 8)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[derive(Clone, Debug)]
+enum ExprKind {
+    Number(i64),
+    Symbol(String),
+    High(Box<Expr>),
+    Low(Box<Expr>),
+    Negate(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+struct Expr {
+    kind: ExprKind,
+    column: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(RegisterIndex),
+    RegisterAddress8(RegisterIndex),
+    RegisterAddress16(RegisterIndex, RegisterIndex),
+    Value(Expr),
+}
+
+#[derive(Debug)]
+enum StatementKind {
+    Instruction(Opcode, Vec<(Operand, usize)>),
+    Bytes(Vec<Expr>),
+}
+
+#[derive(Debug)]
+struct Statement {
+    line: usize,
+    address: usize,
+    kind: StatementKind,
+}
+
+struct Parser<'a> {
+    line: usize,
+    tokens: &'a [Token],
+    position: usize,
+    end_column: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end_column, |token| token.column)
+    }
+
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        error(self.line, self.column(), message)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &str) -> Result<(), AsmError> {
+        if self.peek() == Some(&kind) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {}", what)))
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn register(&mut self) -> Result<RegisterIndex, AsmError> {
+        let error = self.error("expected register");
+        match self.next().map(|token| &token.kind) {
+            Some(TokenKind::Ident(name)) => RegisterIndex::from_name(name).ok_or(error),
+            _ => Err(error),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, AsmError> {
+        let mut left = self.term()?;
+        loop {
+            let column = self.column();
+            let combine: fn(Box<Expr>, Box<Expr>) -> ExprKind = match self.peek() {
+                Some(TokenKind::Plus) => ExprKind::Add,
+                Some(TokenKind::Minus) => ExprKind::Sub,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            let right = self.term()?;
+            left = Expr {
+                kind: combine(Box::new(left), Box::new(right)),
+                column,
+            };
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, AsmError> {
+        let column = self.column();
+        let expected = self.error("expected value");
+        let kind = match self.next().map(|token| &token.kind) {
+            Some(TokenKind::Number(value)) => ExprKind::Number(*value),
+            Some(TokenKind::Minus) => ExprKind::Negate(Box::new(self.term()?)),
+            Some(TokenKind::LParen) => {
+                let inner = self.expr()?;
+                self.expect(TokenKind::RParen, "')'")?;
+                return Ok(inner);
+            }
+            Some(TokenKind::Ident(name)) if self.peek() == Some(&TokenKind::LParen) => {
+                let function: fn(Box<Expr>) -> ExprKind = match name.to_ascii_lowercase().as_str() {
+                    "hi" => ExprKind::High,
+                    "lo" => ExprKind::Low,
+                    _ => {
+                        return Err(error(
+                            self.line,
+                            column,
+                            format!("unknown function '{}'", name),
+                        ))
+                    }
+                };
+                self.position += 1;
+                let inner = self.expr()?;
+                self.expect(TokenKind::RParen, "')'")?;
+                function(Box::new(inner))
+            }
+            Some(TokenKind::Ident(name)) => ExprKind::Symbol(name.clone()),
+            _ => return Err(expected),
+        };
+        Ok(Expr { kind, column })
+    }
+
+    fn operand(&mut self) -> Result<Operand, AsmError> {
+        match self.peek() {
+            Some(TokenKind::LBracket) => {
+                self.position += 1;
+                let high = self.register()?;
+                let operand = if self.peek() == Some(&TokenKind::Colon) {
+                    self.position += 1;
+                    Operand::RegisterAddress16(high, self.register()?)
+                } else {
+                    Operand::RegisterAddress8(high)
+                };
+                self.expect(TokenKind::RBracket, "']'")?;
+                Ok(operand)
+            }
+            Some(TokenKind::Ident(name)) if RegisterIndex::from_name(name).is_some() => {
+                Ok(Operand::Register(self.register()?))
+            }
+            _ => Ok(Operand::Value(self.expr()?)),
+        }
+    }
+}
+
+struct Assembler {
+    layout: MemoryLayout,
+    section: Section,
+    cursors: HashMap<Section, usize>,
+    symbols: HashMap<String, i64>,
+    statements: Vec<Statement>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            layout: MemoryLayout::narrow(),
+            section: Section::Text,
+            cursors: Self::cursors(&MemoryLayout::narrow()),
+            symbols: HashMap::new(),
+            statements: vec![],
+        }
+    }
+
+    fn cursors(layout: &MemoryLayout) -> HashMap<Section, usize> {
+        [Section::Text, Section::Data, Section::Stack]
+            .into_iter()
+            .map(|section| (section, section.range(layout).start))
+            .collect()
+    }
+
+    /// Switches layout, which is only allowed before anything was placed or defined
+    fn set_layout(
+        &mut self,
+        line: usize,
+        column: usize,
+        layout: MemoryLayout,
+    ) -> Result<(), AsmError> {
+        if self.cursors != Self::cursors(&self.layout) || !self.symbols.is_empty() {
+            return Err(error(line, column, "layout directives must come first"));
+        }
+        self.cursors = Self::cursors(&layout);
+        self.layout = layout;
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        line: usize,
+        column: usize,
+        name: &str,
+        value: i64,
+    ) -> Result<(), AsmError> {
+        if RegisterIndex::from_name(name).is_some() {
+            return Err(error(
+                line,
+                column,
+                format!("'{}' is a register name", name),
+            ));
+        }
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(error(line, column, format!("duplicate symbol '{}'", name)));
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        line: usize,
+        column: usize,
+        len: usize,
+        kind: StatementKind,
+    ) -> Result<(), AsmError> {
+        let cursor = self.cursors.get_mut(&self.section).unwrap();
+        let address = *cursor;
+        if address + len > self.section.range(&self.layout).end {
+            return Err(error(
+                line,
+                column,
+                format!("section {} overflows its region", self.section.directive()),
+            ));
+        }
+        *cursor += len;
+        self.statements.push(Statement {
+            line,
+            address,
+            kind,
+        });
+        Ok(())
+    }
+
+    fn line(&mut self, line: usize, text: &str) -> Result<(), AsmError> {
+        let tokens = tokenize(line, text)?;
+        let mut parser = Parser {
+            line,
+            tokens: &tokens,
+            position: 0,
+            end_column: text.chars().count() + 1,
+        };
+        while let (Some(TokenKind::Ident(name)), Some(TokenKind::Colon)) = (
+            parser.peek(),
+            tokens.get(parser.position + 1).map(|token| &token.kind),
+        ) {
+            let column = parser.column();
+            let address = self.cursors[&self.section] as i64;
+            self.define(line, column, name, address)?;
+            parser.position += 2;
+        }
+        let column = parser.column();
+        match parser.next().map(|token| &token.kind) {
+            None => return Ok(()),
+            Some(TokenKind::Directive(directive)) => match directive.as_str() {
+                "text" => self.section = Section::Text,
+                "data" => self.section = Section::Data,
+                "stack" => self.section = Section::Stack,
+                "wide" => self.set_layout(line, column, MemoryLayout::wide())?,
+                "layout" => {
+                    let mut sizes = vec![];
+                    for index in 0..3 {
+                        if index > 0 {
+                            parser.expect(TokenKind::Comma, "','")?;
+                        }
+                        let expr = parser.expr()?;
+                        let size = self.eval(line, &expr)?;
+                        sizes.push(usize::try_from(size).map_err(|_| {
+                            error(line, expr.column, format!("invalid size {:#x}", size))
+                        })?);
+                    }
+                    let layout = MemoryLayout::with_sizes(sizes[0], sizes[1], sizes[2])
+                        .map_err(|layout_error| error(line, column, layout_error.to_string()))?;
+                    self.set_layout(line, column, layout)?;
+                }
+                "equ" => {
+                    let name_column = parser.column();
+                    let Some(TokenKind::Ident(name)) = parser.next().map(|token| &token.kind)
+                    else {
+                        return Err(error(line, name_column, "expected symbol name"));
+                    };
+                    parser.expect(TokenKind::Comma, "','")?;
+                    let expr = parser.expr()?;
+                    let value = self.eval(line, &expr)?;
+                    self.define(line, name_column, name, value)?;
+                }
+                "db" => {
+                    let mut items = vec![];
+                    let mut len = 0;
+                    loop {
+                        let column = parser.column();
+                        if let Some(TokenKind::Str(bytes)) = parser.peek() {
+                            parser.position += 1;
+                            len += bytes.len();
+                            items.extend(bytes.iter().map(|&byte| Expr {
+                                kind: ExprKind::Number(byte as i64),
+                                column,
+                            }));
+                        } else {
+                            items.push(parser.expr()?);
+                            len += 1;
+                        }
+                        if parser.at_end() {
+                            break;
+                        }
+                        parser.expect(TokenKind::Comma, "','")?;
+                    }
+                    self.push(line, column, len, StatementKind::Bytes(items))?;
+                }
+                "zero" => {
+                    let expr = parser.expr()?;
+                    let count = self.eval(line, &expr)?;
+                    if count < 0 {
+                        return Err(error(line, expr.column, "negative count"));
+                    }
+                    self.push(line, column, count as usize, StatementKind::Bytes(vec![]))?;
+                }
+                _ => {
+                    return Err(error(
+                        line,
+                        column,
+                        format!("unknown directive '.{}'", directive),
+                    ))
+                }
+            },
+            Some(TokenKind::Ident(mnemonic)) => {
+                let opcode = Opcode::from_mnemonic(mnemonic).ok_or_else(|| {
+                    error(line, column, format!("unknown mnemonic '{}'", mnemonic))
+                })?;
+                let mut operands = vec![];
+                while !parser.at_end() {
+                    if !operands.is_empty() {
+                        parser.expect(TokenKind::Comma, "','")?;
+                    }
+                    let column = parser.column();
+                    operands.push((parser.operand()?, column));
+                }
+                let expected = opcode.operands().len();
+                if operands.len() != expected {
+                    return Err(error(
+                        line,
+                        column,
+                        format!(
+                            "'{}' takes {} operand(s), got {}",
+                            mnemonic,
+                            expected,
+                            operands.len()
+                        ),
+                    ));
+                }
+                self.push(
+                    line,
+                    column,
+                    opcode.encoded_len() as usize,
+                    StatementKind::Instruction(opcode, operands),
+                )?;
+            }
+            Some(_) => return Err(error(line, column, "expected label, directive or mnemonic")),
+        }
+        if !parser.at_end() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(())
+    }
+
+    fn eval(&self, line: usize, expr: &Expr) -> Result<i64, AsmError> {
+        let overflow = || error(line, expr.column, "expression overflows 64 bits");
+        Ok(match &expr.kind {
+            ExprKind::Number(value) => *value,
+            ExprKind::Symbol(name) => *self
+                .symbols
+                .get(name)
+                .ok_or_else(|| error(line, expr.column, format!("undefined symbol '{}'", name)))?,
+            ExprKind::High(inner) => (self.eval(line, inner)? >> 8) & 0xff,
+            ExprKind::Low(inner) => self.eval(line, inner)? & 0xff,
+            ExprKind::Negate(inner) => {
+                self.eval(line, inner)?.checked_neg().ok_or_else(overflow)?
+            }
+            ExprKind::Add(left, right) => self
+                .eval(line, left)?
+                .checked_add(self.eval(line, right)?)
+                .ok_or_else(overflow)?,
+            ExprKind::Sub(left, right) => self
+                .eval(line, left)?
+                .checked_sub(self.eval(line, right)?)
+                .ok_or_else(overflow)?,
+        })
+    }
+
+    fn byte(&self, line: usize, expr: &Expr) -> Result<u8, AsmError> {
+        let value = self.eval(line, expr)?;
+        if !(-0x80..=0xff).contains(&value) {
+            return Err(error(
+                line,
+                expr.column,
+                format!("value {:#x} does not fit in 8 bits", value),
+            ));
+        }
+        Ok(value as u8)
+    }
+
+    /// Low byte of a target in the 256-byte page starting at `next`'s page, `next` wraps
+    /// at max_pc like PC does in VM::step
+    fn near_address(&self, line: usize, expr: &Expr, next: usize) -> Result<u8, AsmError> {
+        let value = self.eval(line, expr)?;
+        let next = next & self.layout.pc_width().max_pc() as usize;
+        if value >> 8 != (next >> 8) as i64 {
+            return Err(error(
+                line,
+                expr.column,
+                format!(
+                    "target {:#x} is outside page {:#x} of the next instruction, use a far jump",
+                    value,
+                    next & !0xff
+                ),
+            ));
+        }
+        Ok(value as u8)
+    }
+
+    /// Any 16-bit target, a target past the PC's reach faults when the jump runs
+    fn far_address(&self, line: usize, expr: &Expr) -> Result<u16, AsmError> {
+        let value = self.eval(line, expr)?;
+        if !(0..=0xffff).contains(&value) {
+            return Err(error(
+                line,
+                expr.column,
+                format!("target {:#x} does not fit in 16 bits", value),
+            ));
+        }
+        Ok(value as u16)
+    }
+
+    fn emit(self) -> Result<Vec<u8>, AsmError> {
+        let mut image = vec![0u8; self.layout.size()];
+        for statement in &self.statements {
+            let line = statement.line;
+            let bytes = match &statement.kind {
+                StatementKind::Bytes(items) => items
+                    .iter()
+                    .map(|item| self.byte(line, item))
+                    .collect::<Result<Vec<_>, _>>()?,
+                StatementKind::Instruction(opcode, operands) => {
+                    let mut bytes = vec![*opcode as u8];
+                    for (&kind, (operand, column)) in opcode.operands().iter().zip(operands) {
+                        match (kind, operand) {
+                            (OperandKind::Register, Operand::Register(register))
+                            | (
+                                OperandKind::RegisterAddress8,
+                                Operand::RegisterAddress8(register),
+                            ) => bytes.push(register.0),
+                            (
+                                OperandKind::RegisterAddress16,
+                                Operand::RegisterAddress16(high, low),
+                            ) => bytes.extend([high.0, low.0]),
+                            (OperandKind::Const8, Operand::Value(expr)) => {
+                                bytes.push(self.byte(line, expr)?)
+                            }
+                            (OperandKind::Address8, Operand::Value(expr)) => {
+                                let next = statement.address + opcode.encoded_len() as usize;
+                                bytes.push(self.near_address(line, expr, next)?)
+                            }
+                            (OperandKind::Address16, Operand::Value(expr)) => {
+                                bytes.extend(self.far_address(line, expr)?.to_be_bytes())
+                            }
+                            (kind, _) => {
+                                let expected = match kind {
+                                    OperandKind::Register => "a register",
+                                    OperandKind::Const8 => "a value",
+                                    OperandKind::Address8 | OperandKind::Address16 => "an address",
+                                    OperandKind::RegisterAddress8 => "'[register]'",
+                                    OperandKind::RegisterAddress16 => "'[register:register]'",
+                                };
+                                return Err(error(line, *column, format!("expected {}", expected)));
+                            }
+                        }
+                    }
+                    bytes
+                }
+            };
+            image[statement.address..statement.address + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(image)
+    }
+}
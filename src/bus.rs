@@ -0,0 +1,268 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::assembler::parse_number;
+use crate::error::Fault;
+use crate::vm::VM;
+
+/*
+Memory-mapped peripherals. A device claims a range of addresses outside the
+memory layout, loads and stores that land in it are handed to the device
+instead of memory. Offsets passed to a device are relative to the start of
+its range.
+
+    console:  +0 data, reads the next input byte and writes an output byte
+              +1 status, reads 1 once a data read found no more input,
+                 writes are ignored
+    rng:      +0 reads the next random byte, writing reseeds the generator
+ */
+
+pub trait Device {
+    fn name(&self) -> &str;
+
+    /// Number of addresses the device claims
+    fn size(&self) -> u16;
+
+    fn read(&mut self, offset: u16, vm: &mut VM) -> Result<u8, Fault>;
+
+    fn write(&mut self, offset: u16, value: u8, vm: &mut VM) -> Result<(), Fault>;
+
+    /// Internal state for snapshots, nothing for stateless devices
+    fn save(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Takes back what `save` returned
+    fn restore(&mut self, _state: &[u8]) {}
+}
+
+struct Mapping {
+    start: u16,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    fn range(&self) -> Range<usize> {
+        self.start as usize..self.start as usize + self.device.size() as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusError {
+    /// The range runs past the end of the address space
+    OutOfRange { name: String, start: u16 },
+    /// The range overlaps memory or another device
+    Overlap { name: String, other: String },
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::OutOfRange { name, start } => {
+                write!(f, "{} at {:04x} runs past the address space", name, start)
+            }
+            BusError::Overlap { name, other } => write!(f, "{} overlaps {}", name, other),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    /// Claims the device's range starting at `start`, `reserved` is address space the
+    /// device must stay out of
+    pub fn attach(
+        &mut self,
+        start: u16,
+        device: Box<dyn Device>,
+        reserved: Range<usize>,
+    ) -> Result<(), BusError> {
+        let mapping = Mapping { start, device };
+        let range = mapping.range();
+        let name = mapping.device.name().to_string();
+        if range.end > u16::MAX as usize + 1 {
+            return Err(BusError::OutOfRange { name, start });
+        }
+        let overlaps = |other: &Range<usize>| range.start < other.end && other.start < range.end;
+        if overlaps(&reserved) {
+            return Err(BusError::Overlap {
+                name,
+                other: "memory".to_string(),
+            });
+        }
+        if let Some(other) = self.mappings.iter().find(|other| overlaps(&other.range())) {
+            return Err(BusError::Overlap {
+                name,
+                other: other.device.name().to_string(),
+            });
+        }
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    /// Mapped devices and where their ranges start, in the order they were attached
+    pub fn devices(&self) -> impl Iterator<Item = (u16, &dyn Device)> {
+        self.mappings
+            .iter()
+            .map(|mapping| (mapping.start, mapping.device.as_ref()))
+    }
+
+    pub fn devices_mut(&mut self) -> impl Iterator<Item = (u16, &mut (dyn Device + 'static))> {
+        self.mappings
+            .iter_mut()
+            .map(|mapping| (mapping.start, mapping.device.as_mut()))
+    }
+
+    /// The device claiming `address` and the offset into its range
+    pub fn device_at(&mut self, address: u16) -> Option<(u16, &mut (dyn Device + 'static))> {
+        self.mappings
+            .iter_mut()
+            .find(|mapping| mapping.range().contains(&(address as usize)))
+            .map(|mapping| (address - mapping.start, mapping.device.as_mut()))
+    }
+}
+
+#[derive(Default)]
+pub struct ConsoleDevice {
+    exhausted: bool,
+}
+
+impl Device for ConsoleDevice {
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    fn size(&self) -> u16 {
+        2
+    }
+
+    fn read(&mut self, offset: u16, vm: &mut VM) -> Result<u8, Fault> {
+        match offset {
+            0 => {
+                let mut byte = [0];
+                let read = vm.input(&mut byte)?;
+                self.exhausted = read == 0;
+                Ok(byte[0])
+            }
+            _ => Ok(self.exhausted as u8),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8, vm: &mut VM) -> Result<(), Fault> {
+        match offset {
+            0 => vm.output(&[value]),
+            // Status is read-only
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Vec<u8> {
+        vec![self.exhausted as u8]
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        self.exhausted = state.first().is_some_and(|&byte| byte != 0);
+    }
+}
+
+/// xorshift64*, so a seed gives the same bytes on every host
+pub struct RngDevice {
+    state: u64,
+}
+
+impl RngDevice {
+    pub fn new(seed: u64) -> Self {
+        let mut device = Self { state: 0 };
+        device.seed(seed);
+        device
+    }
+
+    fn seed(&mut self, seed: u64) {
+        // Zero is a fixed point of xorshift
+        self.state = (seed ^ 0x9e37_79b9_7f4a_7c15).max(1);
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+    }
+}
+
+impl Device for RngDevice {
+    fn name(&self) -> &str {
+        "rng"
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _offset: u16, _vm: &mut VM) -> Result<u8, Fault> {
+        Ok(self.next_byte())
+    }
+
+    fn write(&mut self, _offset: u16, value: u8, _vm: &mut VM) -> Result<(), Fault> {
+        self.seed(value as u64);
+        Ok(())
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.state.to_le_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let Ok(state) = state.try_into() {
+            self.state = u64::from_le_bytes(state);
+        }
+    }
+}
+
+/// `console@0xff00` or `rng@0xff02`, a built-in device and where it is mapped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub kind: DeviceKind,
+    pub start: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Console,
+    Rng,
+}
+
+impl DeviceSpec {
+    /// Builds the device, `seed` feeds random-number devices
+    pub fn device(&self, seed: u64) -> Box<dyn Device> {
+        match self.kind {
+            DeviceKind::Console => Box::new(ConsoleDevice::default()),
+            DeviceKind::Rng => Box::new(RngDevice::new(seed)),
+        }
+    }
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, start) = text
+            .split_once('@')
+            .ok_or_else(|| format!("expected <device>@<address>, got {}", text))?;
+        let kind = match name {
+            "console" => DeviceKind::Console,
+            "rng" => DeviceKind::Rng,
+            _ => return Err(format!("unknown device {}, expected console or rng", name)),
+        };
+        let start = parse_number(start)
+            .and_then(|start| u16::try_from(start).ok())
+            .ok_or_else(|| format!("invalid address {}", start))?;
+        Ok(Self { kind, start })
+    }
+}
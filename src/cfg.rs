@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::disassembler::{decode_at, format_instruction, jump_targets};
+use crate::error::{DecodeError, VmError};
+use crate::instruction::Opcode;
+use crate::layout::MemoryLayout;
+use crate::unpack::run_to_written_code;
+use crate::vm::{WriteBitmap, VM};
+
+/*
+Control-flow graph recovery. Code is decoded recursively from the entry PC,
+blocks start at jump and call targets and at the fall-through after a
+conditional jump or call, and end at jumps, calls, Ret, Exit and decode
+errors. Self-modifying images are emulated first, up to the point where
+execution enters bytes the program wrote itself or would block on input, and
+the graph is built from that memory.
+ */
+
+/// Instructions emulated before giving up on reaching decoded code
+pub const DEFAULT_EMULATION_STEPS: usize = 1 << 20;
+
+/// Memory after emulating the image's decoding stage
+pub struct Emulation {
+    pub memory: Vec<u8>,
+    /// Instruction bytes the program wrote while running
+    pub written: BTreeSet<u16>,
+}
+
+/// Runs the image until it jumps into code it wrote, is about to read input or exits. A
+/// fault on the way is returned
+pub fn emulate(
+    image: &[u8],
+    layout: &MemoryLayout,
+    max_steps: usize,
+) -> Result<Emulation, VmError> {
+    let mut vm = VM::new(layout.clone());
+    vm.load(image)?;
+    vm.write_bitmap = Some(WriteBitmap::new(vm.memory.len()));
+    let about_to_read = |vm: &VM| {
+        // Any syscall may read input
+        vm.fetch().is_ok_and(|instruction| {
+            matches!(
+                instruction.opcode(),
+                Opcode::ReadStdinStack | Opcode::Syscall
+            )
+        })
+    };
+    // A graph of memory the program never finished decoding would be wrong, a fault is an error
+    run_to_written_code(&mut vm, max_steps, about_to_read)?;
+    let written = vm
+        .write_bitmap
+        .as_ref()
+        .unwrap()
+        .iter()
+        .filter(|&address| vm.layout.instructions().contains(address as usize))
+        .collect();
+    Ok(Emulation {
+        memory: vm.memory.to_vec(),
+        written,
+    })
+}
+
+pub struct Block {
+    pub start: u16,
+    /// Address and assembler text of each instruction
+    pub instructions: Vec<(u16, String)>,
+    pub successors: Vec<Edge>,
+    /// Set when the block ends in bytes that do not decode
+    pub error: Option<DecodeError>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Taken,
+    Call,
+    FallThrough,
+}
+
+#[derive(Clone, Copy)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: u16,
+}
+
+pub struct Cfg {
+    pub entry: u16,
+    pub blocks: BTreeMap<u16, Block>,
+    /// Instruction bytes written at runtime, highlighted in the graph
+    pub written: BTreeSet<u16>,
+}
+
+/// Successors of a single instruction at `address`
+fn successors(
+    memory: &[u8],
+    layout: &MemoryLayout,
+    address: u16,
+) -> Result<(Vec<Edge>, bool), DecodeError> {
+    let instruction = decode_at(memory, layout, address as usize)?;
+    let opcode = instruction.opcode();
+    let kind = match opcode.is_call() {
+        true => EdgeKind::Call,
+        false => EdgeKind::Taken,
+    };
+    let max_pc = layout.pc_width().max_pc();
+    let mut edges = jump_targets(instruction.as_ref(), address, layout.pc_width())
+        .into_iter()
+        .map(|target| Edge { kind, target })
+        .collect::<Vec<_>>();
+    let ends_block = !edges.is_empty() || !opcode.falls_through();
+    let next = address as usize + instruction.len() as usize;
+    if opcode.falls_through() && next <= max_pc as usize {
+        edges.push(Edge {
+            kind: EdgeKind::FallThrough,
+            target: next as u16,
+        });
+    }
+    Ok((edges, ends_block))
+}
+
+impl Cfg {
+    pub fn recover(
+        memory: &[u8],
+        layout: &MemoryLayout,
+        entry: u16,
+        written: BTreeSet<u16>,
+    ) -> Self {
+        // Find every reachable instruction and the leaders among them
+        let mut leaders = BTreeSet::from([entry]);
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![entry];
+        while let Some(address) = pending.pop() {
+            if !visited.insert(address) {
+                continue;
+            }
+            let Ok((edges, ends_block)) = successors(memory, layout, address) else {
+                continue;
+            };
+            for edge in edges {
+                if ends_block {
+                    leaders.insert(edge.target);
+                }
+                pending.push(edge.target);
+            }
+        }
+
+        let label = |address: u16| {
+            leaders
+                .contains(&address)
+                .then(|| format!("L_{:02x}", address))
+        };
+        let mut blocks = BTreeMap::new();
+        for &start in &leaders {
+            let mut block = Block {
+                start,
+                instructions: vec![],
+                successors: vec![],
+                error: None,
+            };
+            let mut address = start;
+            loop {
+                let instruction = match decode_at(memory, layout, address as usize) {
+                    Ok(instruction) => instruction,
+                    Err(error) => {
+                        block.error = Some(error);
+                        break;
+                    }
+                };
+                block.instructions.push((
+                    address,
+                    format_instruction(instruction.as_ref(), address, layout.pc_width(), &label),
+                ));
+                let (edges, ends_block) =
+                    successors(memory, layout, address).expect("instruction decoded above");
+                match edges.as_slice() {
+                    [Edge {
+                        kind: EdgeKind::FallThrough,
+                        target,
+                    }] if !ends_block && !leaders.contains(target) => address = *target,
+                    _ => {
+                        block.successors = edges;
+                        break;
+                    }
+                }
+            }
+            blocks.insert(start, block);
+        }
+        Self {
+            entry,
+            blocks,
+            written,
+        }
+    }
+
+    /// Graphviz source, one node per basic block
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n");
+        dot += "    node [shape=box fontname=\"monospace\"];\n";
+        dot += &format!(
+            "    entry [shape=plaintext];\n    entry -> b_{:02x};\n",
+            self.entry
+        );
+        for block in self.blocks.values() {
+            let mut label = format!("L_{:02x}:\\l", block.start);
+            for (address, text) in &block.instructions {
+                label += &format!("{:02x}: {}\\l", address, escape(text));
+            }
+            if let Some(error) = &block.error {
+                label += &format!("{}\\l", escape(&error.to_string()));
+            }
+            let decoded = block
+                .instructions
+                .iter()
+                .any(|(address, _)| self.written.contains(address));
+            let style = match (&block.error, decoded) {
+                (Some(_), _) => " style=filled fillcolor=\"#f4cccc\"",
+                (None, true) => " style=filled fillcolor=\"#fff2cc\"",
+                (None, false) => "",
+            };
+            dot += &format!(
+                "    b_{:02x} [label=\"{}\"{}];\n",
+                block.start, label, style
+            );
+            for edge in &block.successors {
+                let attributes = match edge.kind {
+                    EdgeKind::Taken => " [label=\"taken\"]",
+                    EdgeKind::Call => " [label=\"call\" style=bold]",
+                    EdgeKind::FallThrough if block.successors.len() > 1 => {
+                        " [label=\"fall through\" style=dashed]"
+                    }
+                    EdgeKind::FallThrough => "",
+                };
+                dot += &format!(
+                    "    b_{:02x} -> b_{:02x}{};\n",
+                    block.start, edge.target, attributes
+                );
+            }
+        }
+        dot + "}\n"
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
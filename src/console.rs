@@ -0,0 +1,102 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Stdin, Stdout, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/*
+Byte streams the program reads with `read` and writes with `write`. The VM
+owns one Console and every I/O instruction goes through it, bytes are passed
+through unchanged in both directions.
+ */
+
+pub trait Console {
+    /// Reads at most `buffer.len()` bytes in one go, fewer when the input has no more ready
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+}
+
+/// Console over any reader and writer
+pub struct Streams<R, W> {
+    pub input: R,
+    pub output: W,
+}
+
+impl<R: Read, W: Write> Console for Streams<R, W> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buffer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+}
+
+pub type Stdio = Streams<Stdin, Stdout>;
+
+impl Stdio {
+    pub fn new() -> Self {
+        Self {
+            input: io::stdin(),
+            output: io::stdout(),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams<File, File> {
+    /// Reads `input` and truncates `output` before writing to it
+    pub fn files(input: impl AsRef<Path>, output: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            input: File::open(input)?,
+            output: File::create(output)?,
+        })
+    }
+}
+
+/// In-memory console, clones share the same input and output
+#[derive(Clone, Default)]
+pub struct Buffer {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Buffer {
+    pub fn new(input: &[u8]) -> Self {
+        let buffer = Self::default();
+        buffer.push_input(input);
+        buffer
+    }
+
+    /// Appends to the bytes the program has yet to read
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    /// Everything the program wrote so far
+    pub fn output(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.take()
+    }
+}
+
+impl Console for Buffer {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.input.borrow_mut().read(buffer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.borrow_mut().extend_from_slice(bytes);
+        Ok(())
+    }
+}
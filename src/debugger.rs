@@ -0,0 +1,480 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::assembler::{parse_number, Section};
+use crate::disassembler::{decode_at, format_instruction};
+use crate::error::VmError;
+use crate::history::{History, Location};
+use crate::layout::{MemoryLayout, PcWidth};
+use crate::registers::RegisterIndex;
+use crate::vm::VM;
+
+const HELP: &str = "\
+step [n]            execute n instructions (s)
+next                run until the instruction after this one (n)
+continue            run until a breakpoint or exit (c)
+reverse-step [n]    undo n instructions (rs)
+reverse-continue    undo until a breakpoint or the start of history (rc)
+last-write <loc>    find the instruction that last wrote a register or address (lw)
+break <addr>        set a breakpoint on PC (b)
+delete [addr]       remove one or all breakpoints (d)
+breakpoints         list breakpoints (bl)
+registers           show registers and flags (r)
+set <reg> <value>   write a register
+x <addr> [len]      examine memory
+patch <addr> <b..>  write bytes to memory
+disasm [addr] [n]   disassemble around PC or at addr (l)
+reset               reload the image
+quit                leave the debugger (q)
+
+Addresses are numbers, registers, or text/data/stack with an optional +offset.
+An empty line repeats the last command.";
+
+const DEFAULT_DISASM_COUNT: usize = 8;
+const DEFAULT_EXAMINE_LEN: usize = 64;
+
+pub struct Debugger {
+    pub vm: VM,
+    pub breakpoints: BTreeSet<u16>,
+    image: Vec<u8>,
+    last: String,
+}
+
+enum Stop {
+    Breakpoint,
+    Exited,
+    Fault(VmError),
+    Done,
+}
+
+impl Debugger {
+    pub fn new(image: Vec<u8>, layout: MemoryLayout) -> Result<Self, VmError> {
+        let mut vm = VM::new(layout);
+        vm.load(&image)?;
+        vm.history = Some(History::default());
+        Ok(Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+            image,
+            last: String::new(),
+        })
+    }
+
+    fn pc(&self) -> u16 {
+        self.vm.pc()
+    }
+
+    /// `read_line` is called once per prompt, the program's own reads share the same input
+    pub fn repl(
+        &mut self,
+        read_line: &mut dyn FnMut(&mut String) -> io::Result<usize>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        self.current(output)?;
+        loop {
+            write!(output, "(x8) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last.clone();
+            }
+            self.last = line.clone();
+            if !self.command(&line, output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs one command line, returns false when the session should end
+    pub fn command(&mut self, line: &str, output: &mut dyn Write) -> io::Result<bool> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some((&name, arguments)) = words.split_first() else {
+            return Ok(true);
+        };
+        if let Err(message) = self.dispatch(name, arguments, output) {
+            match message {
+                CommandError::Quit => return Ok(false),
+                CommandError::Io(error) => return Err(error),
+                CommandError::Usage(message) => writeln!(output, "{}", message)?,
+            }
+        }
+        Ok(true)
+    }
+
+    fn dispatch(
+        &mut self,
+        name: &str,
+        arguments: &[&str],
+        output: &mut dyn Write,
+    ) -> Result<(), CommandError> {
+        let number = |index: usize, default: usize| -> Result<usize, CommandError> {
+            arguments
+                .get(index)
+                .map_or(Ok(default), |argument| self.value(argument))
+        };
+        match name {
+            "s" | "step" => {
+                let count = number(0, 1)?;
+                let stop = self.run_until(count, |_| false);
+                self.report(stop, output)?;
+            }
+            "n" | "next" => {
+                let pc = self.pc();
+                let after = match decode_at(&self.vm.memory, &self.vm.layout, pc as usize) {
+                    Ok(instruction) => {
+                        pc.wrapping_add(instruction.len() as u16)
+                            & self.vm.layout.pc_width().max_pc()
+                    }
+                    Err(_) => pc,
+                };
+                let stop = self.run_until(usize::MAX, |vm| vm.pc() == after);
+                self.report(stop, output)?;
+            }
+            "c" | "continue" => {
+                let stop = self.run_until(usize::MAX, |_| false);
+                self.report(stop, output)?;
+            }
+            "rs" | "reverse-step" => {
+                let count = number(0, 1)?;
+                let undone = (0..count).take_while(|_| self.vm.reverse_step()).count();
+                if undone < count {
+                    writeln!(output, "Reached the start of history")?;
+                }
+                self.current(output)?;
+            }
+            "rc" | "reverse-continue" => {
+                let breakpoints = &self.breakpoints;
+                if self.vm.reverse_until(|vm| breakpoints.contains(&vm.pc())) {
+                    writeln!(output, "Breakpoint hit at {:02x}", self.pc())?;
+                } else {
+                    writeln!(output, "Reached the start of history")?;
+                }
+                self.current(output)?;
+            }
+            "lw" | "last-write" => {
+                let argument = arguments
+                    .first()
+                    .ok_or_else(|| CommandError::Usage("Missing location".to_string()))?;
+                let location = match RegisterIndex::from_name(argument) {
+                    Some(index) => Location::Register(index),
+                    None => Location::Memory(self.address(Some(argument))? as u16),
+                };
+                match self.vm.last_write(location) {
+                    Some(write) => {
+                        writeln!(
+                            output,
+                            "{} was written {} steps ago by the instruction at {:02x}, {:02x} -> {:02x}",
+                            location, write.steps_ago, write.pc, write.old, write.new
+                        )?;
+                        self.disassemble(Some(write.pc as usize), 1, output)?;
+                    }
+                    None => writeln!(output, "No write to {} in history", location)?,
+                }
+            }
+            "b" | "break" => {
+                let address = self.address(arguments.first())?;
+                self.breakpoints.insert(address as u16);
+                writeln!(output, "Breakpoint at {:02x}", address as u16)?;
+            }
+            "d" | "delete" => match arguments.first() {
+                Some(argument) => {
+                    let address = self.value(argument)? as u16;
+                    if !self.breakpoints.remove(&address) {
+                        return Err(CommandError::Usage(format!(
+                            "No breakpoint at {:02x}",
+                            address
+                        )));
+                    }
+                }
+                None => self.breakpoints.clear(),
+            },
+            "bl" | "breakpoints" => {
+                for address in &self.breakpoints {
+                    writeln!(output, "{:02x}", address)?;
+                }
+            }
+            "r" | "registers" => self.registers(output)?,
+            "set" => {
+                let (Some(register), Some(value)) = (arguments.first(), arguments.get(1)) else {
+                    return Err(CommandError::Usage("usage: set <reg> <value>".to_string()));
+                };
+                let index = RegisterIndex::from_name(register)
+                    .ok_or_else(|| CommandError::Usage(format!("Unknown register {}", register)))?;
+                let value = self.value(value)?;
+                self.vm
+                    .registers
+                    .get_mut(index)
+                    .map_err(|fault| CommandError::Usage(fault.to_string()))?
+                    .value = value as u8;
+            }
+            "x" => {
+                let address = self.address(arguments.first())?;
+                let len = number(1, DEFAULT_EXAMINE_LEN)?;
+                let end = (address + len).min(self.vm.memory.len());
+                for (index, chunk) in self.vm.memory[address..end].chunks(16).enumerate() {
+                    let hex = chunk
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<Vec<_>>();
+                    let ascii = chunk
+                        .iter()
+                        .map(|&byte| {
+                            if byte.is_ascii_graphic() {
+                                byte as char
+                            } else {
+                                '.'
+                            }
+                        })
+                        .collect::<String>();
+                    writeln!(
+                        output,
+                        "{:04x}: {:<47}  {}",
+                        address + index * 16,
+                        hex.join(" "),
+                        ascii
+                    )?;
+                }
+            }
+            "patch" => {
+                let address = self.address(arguments.first())?;
+                let bytes = arguments
+                    .iter()
+                    .skip(1)
+                    .map(|argument| self.value(argument).map(|value| value as u8))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if address + bytes.len() > self.vm.memory.len() {
+                    return Err(CommandError::Usage(
+                        "Patch runs past the end of memory".to_string(),
+                    ));
+                }
+                self.vm.memory[address..address + bytes.len()].copy_from_slice(&bytes);
+                self.vm.decode_cache.clear();
+            }
+            "l" | "disasm" => {
+                let start = match arguments.first() {
+                    Some(argument) => Some(self.value(argument)?),
+                    None => None,
+                };
+                let count = number(1, DEFAULT_DISASM_COUNT)?;
+                self.disassemble(start, count, output)?;
+            }
+            "reset" => {
+                let mut vm = VM::new(self.vm.layout.clone());
+                // The program keeps reading from where it left off
+                std::mem::swap(&mut vm.console, &mut self.vm.console);
+                vm.history = Some(History::default());
+                self.vm = vm;
+                self.vm
+                    .load(&self.image)
+                    .map_err(|error| CommandError::Usage(error.to_string()))?;
+                self.current(output)?;
+            }
+            "q" | "quit" => return Err(CommandError::Quit),
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            _ => {
+                return Err(CommandError::Usage(format!(
+                    "Unknown command {}, try help",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, argument: &str) -> Result<usize, CommandError> {
+        let invalid = || CommandError::Usage(format!("Invalid value {}", argument));
+        let (base, offset) = match argument.split_once('+') {
+            Some((base, offset)) => (base, parse_number(offset).ok_or_else(invalid)?),
+            None => (argument, 0),
+        };
+        let section = match base.to_ascii_lowercase().as_str() {
+            "text" => Some(Section::Text),
+            "data" => Some(Section::Data),
+            "stack" => Some(Section::Stack),
+            _ => None,
+        };
+        let base = if let Some(section) = section {
+            section.range(&self.vm.layout).start as i64
+        } else if let Some(index) = RegisterIndex::from_name(base) {
+            self.vm
+                .registers
+                .get(index)
+                .map_err(|fault| CommandError::Usage(fault.to_string()))?
+                .value as i64
+        } else {
+            parse_number(base).ok_or_else(invalid)?
+        };
+        base.checked_add(offset)
+            .and_then(|address| usize::try_from(address).ok())
+            .ok_or_else(invalid)
+    }
+
+    fn address(&self, argument: Option<&&str>) -> Result<usize, CommandError> {
+        let argument =
+            argument.ok_or_else(|| CommandError::Usage("Missing address".to_string()))?;
+        let address = self.value(argument)?;
+        if address >= self.vm.memory.len() {
+            return Err(CommandError::Usage(format!(
+                "Address {:#x} is out of bounds",
+                address
+            )));
+        }
+        Ok(address)
+    }
+
+    /// Steps at most `count` instructions, stopping early on breakpoints, exit, faults or `until`
+    fn run_until(&mut self, count: usize, until: impl Fn(&VM) -> bool) -> Stop {
+        if self.vm.stop {
+            return Stop::Exited;
+        }
+        for executed in 0..count {
+            if executed > 0 && self.breakpoints.contains(&self.pc()) {
+                return Stop::Breakpoint;
+            }
+            if let Err(error) = self.vm.step() {
+                return Stop::Fault(error);
+            }
+            if self.vm.stop {
+                return Stop::Exited;
+            }
+            if until(&self.vm) {
+                break;
+            }
+        }
+        if self.breakpoints.contains(&self.pc()) {
+            return Stop::Breakpoint;
+        }
+        Stop::Done
+    }
+
+    fn report(&self, stop: Stop, output: &mut dyn Write) -> io::Result<()> {
+        output.flush()?;
+        match stop {
+            Stop::Breakpoint => writeln!(output, "Breakpoint hit at {:02x}", self.pc())?,
+            Stop::Exited => {
+                writeln!(output, "Program exited")?;
+                return Ok(());
+            }
+            Stop::Fault(error) => writeln!(output, "Fault: {}", error)?,
+            Stop::Done => {}
+        }
+        self.current(output)
+    }
+
+    fn current(&self, output: &mut dyn Write) -> io::Result<()> {
+        self.disassemble(Some(self.pc() as usize), 1, output)
+    }
+
+    fn disassemble(
+        &self,
+        start: Option<usize>,
+        count: usize,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        let pc = self.pc() as usize;
+        let text = Section::Text.range(&self.vm.layout);
+        let start = start.unwrap_or_else(|| {
+            // Show a few instructions before PC when a linear sweep from 0 lands on it
+            let mut address = text.start;
+            let mut previous = vec![];
+            while address < pc {
+                let Ok(instruction) = decode_at(&self.vm.memory, &self.vm.layout, address) else {
+                    return pc;
+                };
+                previous.push(address);
+                address += instruction.len() as usize;
+            }
+            if address != pc {
+                return pc;
+            }
+            match (count / 2).min(previous.len()) {
+                0 => pc,
+                before => previous[previous.len() - before],
+            }
+        });
+        let mut address = start;
+        for _ in 0..count {
+            if address >= text.end {
+                break;
+            }
+            let marker = if address == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&(address as u16)) {
+                '*'
+            } else {
+                ' '
+            };
+            match decode_at(&self.vm.memory, &self.vm.layout, address) {
+                Ok(instruction) => {
+                    writeln!(
+                        output,
+                        "{}{} {:02x}: {}",
+                        marker,
+                        breakpoint,
+                        address,
+                        format_instruction(
+                            instruction.as_ref(),
+                            address as u16,
+                            self.vm.layout.pc_width(),
+                            &|_| None,
+                        )
+                    )?;
+                    address += instruction.len() as usize;
+                }
+                Err(error) => {
+                    writeln!(
+                        output,
+                        "{}{} {:02x}: .db {:#04x} ; {}",
+                        marker, breakpoint, address, self.vm.memory[address], error
+                    )?;
+                    address += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn registers(&self, output: &mut dyn Write) -> io::Result<()> {
+        let registers = &self.vm.registers.registers;
+        for (row, chunk) in registers.chunks(8).enumerate() {
+            let line = chunk
+                .iter()
+                .enumerate()
+                .map(|(column, register)| {
+                    let name = RegisterIndex((row * 8 + column) as u8).to_string();
+                    format!("{:>3}={:02x}", name, register.value)
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(output, "{}", line)?;
+        }
+        if self.vm.layout.pc_width() == PcWidth::Wide {
+            writeln!(output, "pc={:04x}", self.pc())?;
+        }
+        let flags = self.vm.flags;
+        writeln!(
+            output,
+            "flags: zero={} carry={} sign={} overflow={}",
+            flags.zero() as u8,
+            flags.carry() as u8,
+            flags.sign() as u8,
+            flags.overflow() as u8
+        )?;
+        Ok(())
+    }
+}
+
+enum CommandError {
+    Usage(String),
+    Quit,
+    Io(io::Error),
+}
+
+impl From<io::Error> for CommandError {
+    fn from(error: io::Error) -> Self {
+        CommandError::Io(error)
+    }
+}
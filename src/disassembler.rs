@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::assembler::Section;
+use crate::error::{DecodeError, VmError};
+use crate::instruction::{Exit, Instruction, Opcode, OperandKind};
+use crate::layout::{MemoryLayout, PcWidth};
+use crate::registers::RegisterIndex;
+
+const BYTES_PER_LINE: usize = 16;
+const COMMENT_COLUMN: usize = 28;
+
+/// Decodes the instruction starting at `address`, bounded by the region it lives in
+pub fn decode_at(
+    memory: &[u8],
+    layout: &MemoryLayout,
+    address: usize,
+) -> Result<Box<dyn Instruction>, DecodeError> {
+    let end = layout
+        .regions()
+        .iter()
+        .find(|region| region.contains(address))
+        .map_or(memory.len(), |region| region.range().end.min(memory.len()));
+    <dyn Instruction>::parse(memory.get(address..end).unwrap_or_default())
+}
+
+/// Formats the instruction at `address` in assembler syntax, `label` names jump targets
+/// when it can
+pub fn format_instruction(
+    instruction: &dyn Instruction,
+    address: u16,
+    width: PcWidth,
+    label: &dyn Fn(u16) -> Option<String>,
+) -> String {
+    let opcode = instruction.opcode();
+    let bytes = instruction.encode();
+    let mut operands = vec![];
+    let mut rest = &bytes[1..];
+    for kind in opcode.operands() {
+        let (operand, tail) = rest.split_at(kind.encoded_len() as usize);
+        rest = tail;
+        operands.push(match kind {
+            OperandKind::Register => RegisterIndex(operand[0]).to_string(),
+            OperandKind::RegisterAddress8 => format!("[{}]", RegisterIndex(operand[0])),
+            OperandKind::RegisterAddress16 => format!(
+                "[{}:{}]",
+                RegisterIndex(operand[0]),
+                RegisterIndex(operand[1])
+            ),
+            OperandKind::Address8 => format_target(
+                near_target(address, instruction.len(), operand[0], width),
+                label,
+            ),
+            OperandKind::Address16 => {
+                format_target(u16::from_be_bytes([operand[0], operand[1]]), label)
+            }
+            OperandKind::Const8 => format_const(opcode, operand[0]),
+        });
+    }
+    if operands.is_empty() {
+        opcode.mnemonic().to_string()
+    } else {
+        format!("{} {}", opcode.mnemonic(), operands.join(", "))
+    }
+}
+
+fn format_target(target: u16, label: &dyn Fn(u16) -> Option<String>) -> String {
+    label(target).unwrap_or_else(|| match target {
+        0..=0xff => format!("{:#04x}", target),
+        _ => format!("{:#06x}", target),
+    })
+}
+
+/// Absolute target of an 8-bit jump operand, which stays in the page of the next instruction.
+/// The next instruction's address wraps at `width`'s max_pc like PC does in VM::step
+pub fn near_target(address: u16, len: u8, target: u8, width: PcWidth) -> u16 {
+    address.wrapping_add(len as u16) & width.max_pc() & 0xff00 | target as u16
+}
+
+fn format_const(opcode: Opcode, value: u8) -> String {
+    let printable = value.is_ascii_graphic() && value != b'\'' && value != b'\\';
+    match (opcode, value) {
+        (Opcode::WriteStdoutConst8, b'\n') => "'\\n'".to_string(),
+        (Opcode::WriteStdoutConst8, _) if printable || value == b' ' => {
+            format!("'{}'", value as char)
+        }
+        _ => format!("{:#04x}", value),
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn db(bytes: &[u8]) -> String {
+    format!(
+        ".db {}",
+        bytes
+            .iter()
+            .map(|byte| format!("{:#04x}", byte))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+enum Item {
+    Instruction(Box<dyn Instruction>),
+    Data(Vec<u8>),
+    Zero(usize),
+}
+
+fn zero_run(bytes: &[u8]) -> usize {
+    bytes.iter().take_while(|&&byte| byte == 0).count()
+}
+
+/// Linear sweep over the instruction region, bytes that don't decode become data
+fn sweep(image: &[u8], layout: &MemoryLayout) -> BTreeMap<usize, Item> {
+    let text = &image[layout.instructions().range()];
+    let end = text
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+    let mut items = BTreeMap::new();
+    let mut address = 0;
+    while address < end {
+        match <dyn Instruction>::parse(&text[address..]) {
+            Ok(instruction) => {
+                let len = instruction.len() as usize;
+                items.insert(address, Item::Instruction(instruction));
+                address += len;
+                // Keep the first `exit` of a long zero run and collapse the rest
+                let run = zero_run(&text[address.min(end)..end]);
+                if bytes_exit(&text[address - len..address]) && run >= BYTES_PER_LINE {
+                    items.insert(address, Item::Zero(run));
+                    address += run;
+                }
+            }
+            Err(DecodeError::UnknownOpcode(opcode)) => {
+                items.insert(address, Item::Data(vec![opcode]));
+                address += 1;
+            }
+            Err(DecodeError::Truncated) => {
+                items.insert(address, Item::Data(text[address..].to_vec()));
+                address = text.len();
+            }
+        }
+    }
+    if address < text.len() {
+        // Zero padding decodes as a run of `exit`, keep only the first one
+        items.insert(address, Item::Instruction(Box::new(Exit)));
+    }
+    items
+}
+
+/// Annotated listing of a flat image that re-assembles to the same bytes
+pub fn disassemble(image: &[u8], layout: &MemoryLayout) -> Result<String, VmError> {
+    if image.len() != layout.size() {
+        return Err(VmError::InvalidImageSize {
+            expected: layout.size(),
+            actual: image.len(),
+        });
+    }
+    let items = sweep(image, layout);
+    let labels = items
+        .iter()
+        .filter_map(|(address, item)| match item {
+            Item::Instruction(instruction) => Some((address, instruction)),
+            Item::Data(_) | Item::Zero(_) => None,
+        })
+        .flat_map(|(&address, instruction)| {
+            jump_targets(instruction.as_ref(), address as u16, layout.pc_width())
+        })
+        .filter(|target| matches!(items.get(&(*target as usize)), Some(Item::Instruction(_))))
+        .map(|target| (target, format!("L_{:02x}", target)))
+        .collect::<BTreeMap<_, _>>();
+    let label = |target: u16| labels.get(&target).cloned();
+
+    let mut listing = if layout.sizes() == MemoryLayout::narrow().sizes() {
+        String::new()
+    } else if layout.sizes() == MemoryLayout::wide().sizes() {
+        "    .wide\n".to_string()
+    } else {
+        let sizes = layout.sizes().map(|size| format!("{:#x}", size));
+        format!("    .layout {}\n", sizes.join(", "))
+    };
+    listing += "    .text\n";
+    for (&address, item) in &items {
+        let (text, bytes) = match item {
+            Item::Instruction(instruction) => {
+                if let Some(name) = labels.get(&(address as u16)) {
+                    writeln!(listing, "{}:", name).unwrap();
+                }
+                (
+                    format_instruction(
+                        instruction.as_ref(),
+                        address as u16,
+                        layout.pc_width(),
+                        &label,
+                    ),
+                    instruction.encode(),
+                )
+            }
+            Item::Data(bytes) => (db(bytes), bytes.clone()),
+            Item::Zero(count) => (format!(".zero {}", count), vec![]),
+        };
+        let mut comment = format!("{:04x}", address);
+        if !bytes.is_empty() {
+            comment += &format!(": {}", hex_bytes(&bytes));
+        }
+        writeln!(
+            listing,
+            "    {:<width$} ; {}",
+            text,
+            comment,
+            width = COMMENT_COLUMN
+        )
+        .unwrap();
+    }
+    for section in [Section::Data, Section::Stack] {
+        let region = &image[section.range(layout)];
+        let Some(last) = region.iter().rposition(|&byte| byte != 0) else {
+            continue;
+        };
+        writeln!(listing, "    {}", section.directive()).unwrap();
+        let mut offset = 0;
+        while offset <= last {
+            let address = section.range(layout).start + offset;
+            let run = zero_run(&region[offset..=last]);
+            let text = if run >= BYTES_PER_LINE {
+                offset += run;
+                format!(".zero {}", run)
+            } else {
+                let chunk = &region[offset..=last.min(offset + BYTES_PER_LINE - 1)];
+                offset += chunk.len();
+                db(chunk)
+            };
+            writeln!(
+                listing,
+                "    {:<width$} ; {:04x}",
+                text,
+                address,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+        }
+    }
+    Ok(listing)
+}
+
+fn bytes_exit(bytes: &[u8]) -> bool {
+    bytes == [Opcode::Exit as u8]
+}
+
+/// Absolute jump targets of the instruction at `address`
+pub fn jump_targets(instruction: &dyn Instruction, address: u16, width: PcWidth) -> Vec<u16> {
+    let bytes = instruction.encode();
+    let mut offset = 1;
+    let mut targets = vec![];
+    for kind in instruction.opcode().operands() {
+        match kind {
+            OperandKind::Address8 => targets.push(near_target(
+                address,
+                instruction.len(),
+                bytes[offset],
+                width,
+            )),
+            OperandKind::Address16 => {
+                targets.push(u16::from_be_bytes([bytes[offset], bytes[offset + 1]]))
+            }
+            _ => {}
+        }
+        offset += kind.encoded_len() as usize;
+    }
+    targets
+}
@@ -0,0 +1,140 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::layout::PermissionOverride;
+use crate::limits::Limit;
+use crate::vm::AccessKind;
+
+/// Reason an instruction could not be decoded from a byte stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode(u8),
+    Truncated,
+}
+
+/// A fault raised while executing an already decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    StackFull,
+    StackEmpty,
+    InvalidRegister(u8),
+    AddressOutOfBounds(u16),
+    /// The region holding `address` does not permit this kind of access
+    AccessViolation {
+        address: u16,
+        kind: AccessKind,
+        /// Permissions for the region that would let the access through
+        allowed_by: PermissionOverride,
+    },
+    ProgramCounterOverflow,
+    Io(io::ErrorKind),
+    /// `syscall` with no handler registered for R0
+    UnknownSyscall(u8),
+    /// The run used up one of VM::limits
+    LimitExceeded(Limit),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    InvalidImageSize {
+        expected: usize,
+        actual: usize,
+    },
+    UnknownOpcode {
+        pc: u16,
+        opcode: u8,
+    },
+    TruncatedInstruction {
+        pc: u16,
+        bytes: Vec<u8>,
+    },
+    Fault {
+        pc: u16,
+        bytes: Vec<u8>,
+        fault: Fault,
+    },
+}
+
+impl VmError {
+    /// The limit that stopped the run, as opposed to a fault of the program itself
+    pub fn limit(&self) -> Option<Limit> {
+        match self {
+            VmError::Fault {
+                fault: Fault::LimitExceeded(limit),
+                ..
+            } => Some(*limit),
+            _ => None,
+        }
+    }
+
+    pub fn decode(pc: u16, bytes: &[u8], error: DecodeError) -> Self {
+        match error {
+            DecodeError::UnknownOpcode(opcode) => VmError::UnknownOpcode { pc, opcode },
+            DecodeError::Truncated => VmError::TruncatedInstruction {
+                pc,
+                bytes: bytes.to_vec(),
+            },
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:02x}", opcode),
+            DecodeError::Truncated => write!(f, "truncated instruction"),
+        }
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::StackFull => write!(f, "stack is full"),
+            Fault::StackEmpty => write!(f, "stack is empty"),
+            Fault::InvalidRegister(index) => write!(f, "invalid register {}", index),
+            Fault::AddressOutOfBounds(address) => {
+                write!(f, "address {:04x} is out of bounds", address)
+            }
+            Fault::AccessViolation {
+                address,
+                kind,
+                allowed_by,
+            } => write!(
+                f,
+                "{} access to {:04x} is not permitted (--perm {} allows it)",
+                kind, address, allowed_by
+            ),
+            Fault::ProgramCounterOverflow => write!(f, "program counter overflow"),
+            Fault::Io(kind) => write!(f, "I/O error: {}", kind),
+            Fault::UnknownSyscall(number) => write!(f, "unknown syscall {}", number),
+            Fault::LimitExceeded(limit) => write!(f, "{} reached", limit),
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hex = |bytes: &[u8]| hex::encode(bytes);
+        match self {
+            VmError::InvalidImageSize { expected, actual } => write!(
+                f,
+                "invalid address space: expected {:#x} bytes, got {:#x}",
+                expected, actual
+            ),
+            VmError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:02x} at pc {:02x}", opcode, pc)
+            }
+            VmError::TruncatedInstruction { pc, bytes } => {
+                write!(f, "truncated instruction at pc {:02x} [{}]", pc, hex(bytes))
+            }
+            VmError::Fault { pc, bytes, fault } => {
+                write!(f, "{} at pc {:02x} [{}]", fault, pc, hex(bytes))
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl std::error::Error for VmError {}
@@ -0,0 +1,480 @@
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::assembler::parse_number;
+use crate::error::VmError;
+use crate::history::{History, Location};
+use crate::layout::PcWidth;
+use crate::registers::{Register, RegisterIndex};
+use crate::vm::{Flags, VM};
+
+/*
+GDB Remote Serial Protocol stub. Registers are the 16 entries of RegisterSet
+followed by Flags, 8 bits each, in that order. With a 16-bit PC, `pc` is the
+little-endian pair [Register::PCH:Register::PC] and r10 still shows the high
+byte on its own. Addresses map directly onto VM::memory.
+
+Steps are recorded in VM::history, so `reverse-stepi` and `reverse-continue`
+work, and `monitor last-write <register|address>` names the instruction that
+last wrote a location.
+ */
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Steps between checks for a ^C from the client while continuing
+const INTERRUPT_POLL_INTERVAL: usize = 0x1000;
+
+const REGISTER_COUNT: usize = 17;
+const FLAGS_REGNUM: usize = 16;
+
+/// Size of register `regnum` in the `g`/`p` packets
+fn register_len(width: PcWidth, regnum: usize) -> usize {
+    match (width, regnum) {
+        (PcWidth::Wide, 8) => 2,
+        _ => 1,
+    }
+}
+
+pub fn target_xml(width: PcWidth) -> String {
+    let mut registers = String::new();
+    for index in 0..16 {
+        let (name, kind) = match index {
+            8 => ("pc".to_string(), "code_ptr"),
+            9 => ("sp".to_string(), "data_ptr"),
+            _ => (format!("r{}", index), "uint8"),
+        };
+        registers += &format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n",
+            name,
+            register_len(width, index) * 8,
+            kind,
+            index
+        );
+    }
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         \x20 <feature name=\"org.x8.core\">\n\
+         \x20   <flags id=\"x8_flags\" size=\"1\">\n\
+         \x20     <field name=\"zero\" start=\"0\" end=\"0\"/>\n\
+         \x20     <field name=\"carry\" start=\"1\" end=\"1\"/>\n\
+         \x20     <field name=\"sign\" start=\"2\" end=\"2\"/>\n\
+         \x20     <field name=\"overflow\" start=\"3\" end=\"3\"/>\n\
+         \x20   </flags>\n\
+         {}\
+         \x20   <reg name=\"flags\" bitsize=\"8\" type=\"x8_flags\" regnum=\"{}\"/>\n\
+         \x20 </feature>\n\
+         </target>\n",
+        registers, FLAGS_REGNUM
+    )
+}
+
+pub struct GdbStub {
+    pub vm: VM,
+    pub breakpoints: BTreeSet<u16>,
+}
+
+enum Reply {
+    Packet(String),
+    /// Send the packet, then close the connection
+    Close(String),
+}
+
+impl GdbStub {
+    pub fn new(mut vm: VM) -> Self {
+        vm.history.get_or_insert_with(History::default);
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Listens on localhost and serves a single client until it detaches or kills the target
+    pub fn serve(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+        let (mut stream, address) = listener.accept()?;
+        eprintln!("gdb connected from {}", address);
+        stream.set_nodelay(true)?;
+        loop {
+            let Some(packet) = read_packet(&mut stream)? else {
+                return Ok(());
+            };
+            match self.handle(&packet, &mut stream) {
+                Reply::Packet(reply) => write_packet(&mut stream, &reply)?,
+                Reply::Close(reply) => {
+                    write_packet(&mut stream, &reply)?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str, stream: &mut TcpStream) -> Reply {
+        let reply = |text: &str| Reply::Packet(text.to_string());
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" if self.vm.stop => reply(&self.exit_reply()),
+            "?" => reply(&self.stop_reply(SIGTRAP)),
+            "g" => reply(&self.read_registers()),
+            "G" => reply(self.write_registers(arguments).map_or("E01", |_| "OK")),
+            "p" => match usize::from_str_radix(arguments, 16)
+                .ok()
+                .and_then(|regnum| self.register(regnum))
+            {
+                Some(value) => reply(&hex::encode(value)),
+                None => reply("E01"),
+            },
+            "P" => reply(self.write_register(arguments).map_or("E01", |_| "OK")),
+            "m" => reply(
+                &self
+                    .read_memory(arguments)
+                    .unwrap_or_else(|| "E01".to_string()),
+            ),
+            "M" => reply(self.write_memory(arguments).map_or("E01", |_| "OK")),
+            "s" => {
+                let signal = self.resume(arguments, 1, stream);
+                reply(&signal)
+            }
+            "c" => {
+                let signal = self.resume(arguments, usize::MAX, stream);
+                reply(&signal)
+            }
+            "b" => match arguments {
+                "s" => reply(&self.reverse(true)),
+                "c" => reply(&self.reverse(false)),
+                _ => reply(""),
+            },
+            "Z" | "z" => match self.breakpoint(command == "Z", arguments) {
+                Some(true) => reply("OK"),
+                Some(false) => reply(""),
+                None => reply("E01"),
+            },
+            "H" => reply("OK"),
+            "k" => Reply::Close(String::new()),
+            "D" => Reply::Close("OK".to_string()),
+            "q" => reply(&self.query(arguments)),
+            _ => reply(""),
+        }
+    }
+
+    fn query(&self, arguments: &str) -> String {
+        if arguments.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+"
+                .to_string();
+        }
+        if let Some(annex) = arguments.strip_prefix("Xfer:features:read:") {
+            let Some((name, range)) = annex.split_once(':') else {
+                return "E01".to_string();
+            };
+            if name != "target.xml" {
+                return "E00".to_string();
+            }
+            let Some((offset, length)) = parse_pair(range, ',') else {
+                return "E01".to_string();
+            };
+            let xml = target_xml(self.vm.layout.pc_width());
+            let start = offset.min(xml.len());
+            let end = (start + length).min(xml.len());
+            let prefix = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", prefix, escape(&xml[start..end]));
+        }
+        if let Some(command) = arguments.strip_prefix("Rcmd,") {
+            let Some(command) = hex::decode(command)
+                .ok()
+                .and_then(|command| String::from_utf8(command).ok())
+            else {
+                return "E01".to_string();
+            };
+            return hex::encode(self.monitor(&command) + "\n");
+        }
+        match arguments {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Register contents in target byte order
+    fn register(&self, regnum: usize) -> Option<Vec<u8>> {
+        match regnum {
+            FLAGS_REGNUM => Some(vec![self.vm.flags.into_bits()]),
+            8 if self.vm.layout.pc_width() == PcWidth::Wide => {
+                Some(self.vm.pc().to_le_bytes().to_vec())
+            }
+            _ => self
+                .vm
+                .registers
+                .registers
+                .get(regnum)
+                .map(|register| vec![register.value]),
+        }
+    }
+
+    fn set_register(&mut self, regnum: usize, value: &[u8]) -> Option<()> {
+        if value.len() != register_len(self.vm.layout.pc_width(), regnum) {
+            return None;
+        }
+        match regnum {
+            FLAGS_REGNUM => self.vm.flags = Flags::from_bits(value[0]),
+            8 if self.vm.layout.pc_width() == PcWidth::Wide => {
+                self.vm.set_pc(u16::from_le_bytes([value[0], value[1]]))
+            }
+            _ => self.vm.registers.registers.get_mut(regnum)?.value = value[0],
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT)
+            .filter_map(|regnum| self.register(regnum))
+            .map(hex::encode)
+            .collect()
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> Option<()> {
+        let values = hex::decode(arguments).ok()?;
+        let width = self.vm.layout.pc_width();
+        let lengths = (0..REGISTER_COUNT).map(|regnum| register_len(width, regnum));
+        if values.len() != lengths.clone().sum::<usize>() {
+            return None;
+        }
+        let mut rest = values.as_slice();
+        for (regnum, len) in lengths.enumerate() {
+            let (value, tail) = rest.split_at(len);
+            rest = tail;
+            self.set_register(regnum, value)?;
+        }
+        Some(())
+    }
+
+    fn write_register(&mut self, arguments: &str) -> Option<()> {
+        let (regnum, value) = arguments.split_once('=')?;
+        let regnum = usize::from_str_radix(regnum, 16).ok()?;
+        let value = hex::decode(value).ok()?;
+        self.set_register(regnum, &value)
+    }
+
+    fn memory_range(&self, address: usize, length: usize) -> Option<std::ops::Range<usize>> {
+        let end = address.checked_add(length)?;
+        (end <= self.vm.memory.len()).then_some(address..end)
+    }
+
+    fn read_memory(&self, arguments: &str) -> Option<String> {
+        let (address, length) = parse_pair(arguments, ',')?;
+        let range = self.memory_range(address, length)?;
+        Some(hex::encode(&self.vm.memory[range]))
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> Option<()> {
+        let (location, data) = arguments.split_once(':')?;
+        let (address, length) = parse_pair(location, ',')?;
+        let bytes = hex::decode(data).ok()?;
+        if bytes.len() != length {
+            return None;
+        }
+        let range = self.memory_range(address, length)?;
+        self.vm.memory[range].copy_from_slice(&bytes);
+        self.vm.decode_cache.clear();
+        Some(())
+    }
+
+    /// Returns Some(true) when handled, Some(false) for unsupported breakpoint types
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> Option<bool> {
+        let mut parts = arguments.split(',');
+        let kind = parts.next()?;
+        let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+        if kind != "0" {
+            return Some(false);
+        }
+        let address = u16::try_from(address).ok()?;
+        if insert {
+            self.breakpoints.insert(address);
+        } else {
+            self.breakpoints.remove(&address);
+        }
+        Some(true)
+    }
+
+    fn resume(&mut self, arguments: &str, count: usize, stream: &mut TcpStream) -> String {
+        if !arguments.is_empty() {
+            match usize::from_str_radix(arguments, 16) {
+                Ok(address) => match u16::try_from(address) {
+                    Ok(address) if address <= self.vm.layout.pc_width().max_pc() => {
+                        self.vm.set_pc(address)
+                    }
+                    _ => return "E01".to_string(),
+                },
+                Err(_) => return "E01".to_string(),
+            }
+        }
+        if self.vm.stop {
+            return self.exit_reply();
+        }
+        for executed in 0..count {
+            if executed > 0 && self.breakpoints.contains(&self.vm.pc()) {
+                break;
+            }
+            if executed % INTERRUPT_POLL_INTERVAL == INTERRUPT_POLL_INTERVAL - 1
+                && interrupted(stream)
+            {
+                return self.stop_reply(SIGINT);
+            }
+            match self.vm.step() {
+                Ok(()) if self.vm.stop => return self.exit_reply(),
+                Ok(()) => {}
+                Err(VmError::UnknownOpcode { .. } | VmError::TruncatedInstruction { .. }) => {
+                    return self.stop_reply(SIGILL)
+                }
+                Err(_) => return self.stop_reply(SIGSEGV),
+            }
+        }
+        self.stop_reply(SIGTRAP)
+    }
+
+    /// Undoes one step or continues backwards to a breakpoint
+    fn reverse(&mut self, step: bool) -> String {
+        let breakpoints = &self.breakpoints;
+        let stopped = if step {
+            self.vm.reverse_step()
+        } else {
+            self.vm.reverse_until(|vm| breakpoints.contains(&vm.pc()))
+        };
+        if stopped {
+            self.stop_reply(SIGTRAP)
+        } else {
+            // Tells gdb there is no history left
+            format!("{}replaylog:begin;", self.stop_reply(SIGTRAP))
+        }
+    }
+
+    fn monitor(&self, command: &str) -> String {
+        let words = command.split_whitespace().collect::<Vec<_>>();
+        let location = match words.as_slice() {
+            ["last-write", location] => match RegisterIndex::from_name(location) {
+                Some(index) => Location::Register(index),
+                None => {
+                    match parse_number(location).and_then(|address| u16::try_from(address).ok()) {
+                        Some(address) => Location::Memory(address),
+                        None => return format!("Invalid location {}", location),
+                    }
+                }
+            },
+            _ => return "usage: monitor last-write <register|address>".to_string(),
+        };
+        match self.vm.last_write(location) {
+            Some(write) => format!(
+                "{} was written {} steps ago by the instruction at {:04x}, {:02x} -> {:02x}",
+                location, write.steps_ago, write.pc, write.old, write.new
+            ),
+            None => format!("No write to {} in history", location),
+        }
+    }
+
+    /// The program has exited with the status it passed to `exit`
+    fn exit_reply(&self) -> String {
+        format!("W{:02x}", self.vm.status)
+    }
+
+    fn stop_reply(&self, signal: u8) -> String {
+        format!(
+            "T{:02x}{:02x}:{};",
+            signal,
+            Register::PC.0,
+            hex::encode(self.register(Register::PC.0 as usize).unwrap_or_default())
+        )
+    }
+}
+
+fn parse_pair(text: &str, separator: char) -> Option<(usize, usize)> {
+    let (first, second) = text.split_once(separator)?;
+    Some((
+        usize::from_str_radix(first, 16).ok()?,
+        usize::from_str_radix(second, 16).ok()?,
+    ))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '#' | '$' | '}' | '*') {
+            escaped.push('}');
+            escaped.push((c as u8 ^ 0x20) as char);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    loop {
+        write!(stream, "${}#{:02x}", data, checksum(data))?;
+        stream.flush()?;
+        let mut ack = [0];
+        if stream.read(&mut ack)? == 0 || ack[0] != b'-' {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the next packet, acknowledging it, or None once the client disconnects
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = vec![];
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn interrupted(stream: &mut TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let result = stream.read(&mut byte);
+    let _ = stream.set_nonblocking(false);
+    match result {
+        Ok(1) => byte[0] == 0x03,
+        Err(error) if error.kind() == ErrorKind::WouldBlock => false,
+        _ => false,
+    }
+}
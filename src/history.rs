@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+use crate::registers::RegisterIndex;
+
+/*
+Undo logs for reverse execution. While VM::history is Some, every step records
+the PC it started from and the old value of everything it changes: registers,
+memory, flags, the exit state and the state of devices and of VM::rng it
+touched. Undoing a step puts those back. Input a reversed step read is handed
+out again by the next reads, so running forward again sees the same bytes.
+Output already written and the state of custom syscall handlers are not taken
+back.
+
+Registers are compared before and after the step, a register write that keeps
+the value it had is not recorded. Every memory store is.
+ */
+
+/// Steps kept by History::default, the oldest are dropped past it
+pub const DEFAULT_CAPACITY: usize = 1_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Register {
+        index: u8,
+        old: u8,
+    },
+    Memory {
+        address: u16,
+        old: u8,
+    },
+    Flags(u8),
+    Exit {
+        stop: bool,
+        status: u8,
+    },
+    /// What Device::save returned before the step first touched the device
+    Device {
+        start: u16,
+        state: Vec<u8>,
+    },
+    /// What RngDevice::save returned for VM::rng before the step drew a byte from it
+    Rng(Vec<u8>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UndoStep {
+    pub pc: u16,
+    /// In the order they happened, undone last to first
+    pub changes: Vec<Change>,
+    /// Bytes read from the input
+    pub input: Vec<u8>,
+    /// Number of bytes written to the output
+    pub output: u64,
+    /// Whether the step counted towards usage.instructions, false when it faulted
+    pub completed: bool,
+}
+
+impl UndoStep {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.input.is_empty() && self.output == 0 && !self.completed
+    }
+}
+
+/// A register or memory byte, for History::last_write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Register(RegisterIndex),
+    Memory(u16),
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Register(index) => write!(f, "{}", index),
+            Location::Memory(address) => write!(f, "[{:04x}]", address),
+        }
+    }
+}
+
+/// The most recent recorded change to a location
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LastWrite {
+    /// PC of the instruction that wrote it
+    pub pc: u16,
+    /// Number of steps since, 0 for the step just taken
+    pub steps_ago: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+pub struct History {
+    steps: VecDeque<UndoStep>,
+    capacity: usize,
+    /// The step VM::step is recording
+    current: Option<UndoStep>,
+    /// Input given back by reversed steps, read before the console
+    replay: VecDeque<u8>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            steps: VecDeque::new(),
+            capacity,
+            current: None,
+            replay: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Recorded steps, oldest first
+    pub fn steps(&self) -> impl DoubleEndedIterator<Item = &UndoStep> {
+        self.steps.iter()
+    }
+
+    /// Forgets every recorded step and the input they gave back
+    pub fn clear(&mut self) {
+        self.steps.clear();
+        self.current = None;
+        self.replay.clear();
+    }
+
+    pub(crate) fn begin(&mut self, pc: u16) {
+        self.current = Some(UndoStep {
+            pc,
+            ..UndoStep::default()
+        });
+    }
+
+    pub(crate) fn record(&mut self, change: Change) {
+        if let Some(step) = &mut self.current {
+            step.changes.push(change);
+        }
+    }
+
+    /// Whether the current step already saved the state of the device at `start`
+    pub(crate) fn saved_device(&self, start: u16) -> bool {
+        self.current.as_ref().is_some_and(|step| {
+            step.changes.iter().any(
+                |change| matches!(change, Change::Device { start: saved, .. } if *saved == start),
+            )
+        })
+    }
+
+    pub(crate) fn record_input(&mut self, bytes: &[u8]) {
+        if let Some(step) = &mut self.current {
+            step.input.extend(bytes);
+        }
+    }
+
+    pub(crate) fn record_output(&mut self, len: usize) {
+        if let Some(step) = &mut self.current {
+            step.output += len as u64;
+        }
+    }
+
+    /// Takes up to `buffer.len()` bytes of given back input, 0 when there are none
+    pub(crate) fn replay(&mut self, buffer: &mut [u8]) -> usize {
+        let len = buffer.len().min(self.replay.len());
+        for (slot, byte) in buffer.iter_mut().zip(self.replay.drain(..len)) {
+            *slot = byte;
+        }
+        len
+    }
+
+    /// Finishes the current step, steps that changed nothing are dropped
+    pub(crate) fn commit(&mut self, completed: bool) {
+        let Some(mut step) = self.current.take() else {
+            return;
+        };
+        step.completed = completed;
+        if step.is_empty() || self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+
+    /// Removes the latest step, its input is read again by the steps after it
+    pub(crate) fn pop(&mut self) -> Option<UndoStep> {
+        let step = self.steps.pop_back()?;
+        for &byte in step.input.iter().rev() {
+            self.replay.push_front(byte);
+        }
+        Some(step)
+    }
+
+    /// The latest recorded write to `location`, `current` is its value now
+    pub fn last_write(&self, location: Location, current: u8) -> Option<LastWrite> {
+        self.steps
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(steps_ago, step)| {
+                let old =
+                    step.changes
+                        .iter()
+                        .rev()
+                        .find_map(|change| match (change, location) {
+                            (&Change::Register { index, old }, Location::Register(register))
+                                if index == register.0 =>
+                            {
+                                Some(old)
+                            }
+                            (&Change::Memory { address, old }, Location::Memory(target))
+                                if address == target =>
+                            {
+                                Some(old)
+                            }
+                            _ => None,
+                        })?;
+                Some(LastWrite {
+                    pc: step.pc,
+                    steps_ago,
+                    old,
+                    new: current,
+                })
+            })
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
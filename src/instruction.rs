@@ -1,12 +1,10 @@
-use std::io;
-use std::io::Read;
-
-use strum::FromRepr;
+use strum::{EnumIter, FromRepr, IntoEnumIterator};
 
+use crate::error::{DecodeError, Fault};
 use crate::registers::{Register, RegisterIndex};
-use crate::vm::{AddressReg16, VM};
+use crate::vm::{Address16, AddressReg16, Flags, VM};
 
-#[derive(FromRepr)]
+#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Opcode {
     Exit,
     MovReg8Const8,
@@ -22,8 +20,280 @@ pub enum Opcode {
     WriteStdoutConst8,
     CmpReg8Reg8,
     XorReg8Const8,
+    Jump,
+    JumpIfEqual,
+    JumpIfLess,
+    JumpIfGreater,
+    JumpIfLessOrEqual,
+    JumpIfGreaterOrEqual,
+    JumpIfBelow,
+    JumpIfAbove,
+    JumpIfBelowOrEqual,
+    JumpIfAboveOrEqual,
+    Call,
+    Ret,
+    PushReg8,
+    PushConst8,
+    StoreAddressReg16Reg8,
+    StoreAddressReg16Const8,
+    XorAddressReg16Const8,
+    AddAddressReg16Const8,
+    JumpFar,
+    JumpFarIfEqual,
+    JumpFarIfNotEqual,
+    JumpFarIfLess,
+    JumpFarIfGreater,
+    JumpFarIfLessOrEqual,
+    JumpFarIfGreaterOrEqual,
+    JumpFarIfBelow,
+    JumpFarIfAbove,
+    JumpFarIfBelowOrEqual,
+    JumpFarIfAboveOrEqual,
+    CallFar,
+    RetFar,
+    Syscall,
+}
+
+/// When a jump is taken, in terms of the flags left by a compare `a - b`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Condition {
+    Always,
+    Equal,
+    NotEqual,
+    /// Signed `a < b`
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+    /// Unsigned `a < b`
+    Below,
+    Above,
+    BelowOrEqual,
+    AboveOrEqual,
+}
+
+impl Condition {
+    pub fn holds(&self, flags: Flags) -> bool {
+        let signed_less = flags.sign() != flags.overflow();
+        match self {
+            Condition::Always => true,
+            Condition::Equal => flags.zero(),
+            Condition::NotEqual => !flags.zero(),
+            Condition::Less => signed_less,
+            Condition::Greater => !flags.zero() && !signed_less,
+            Condition::LessOrEqual => flags.zero() || signed_less,
+            Condition::GreaterOrEqual => !signed_less,
+            Condition::Below => flags.carry(),
+            Condition::Above => !flags.zero() && !flags.carry(),
+            Condition::BelowOrEqual => flags.zero() || flags.carry(),
+            Condition::AboveOrEqual => !flags.carry(),
+        }
+    }
+
+    /// The condition that holds exactly when this one does not, None for Always
+    pub fn negate(&self) -> Option<Condition> {
+        Some(match self {
+            Condition::Always => return None,
+            Condition::Equal => Condition::NotEqual,
+            Condition::NotEqual => Condition::Equal,
+            Condition::Less => Condition::GreaterOrEqual,
+            Condition::Greater => Condition::LessOrEqual,
+            Condition::LessOrEqual => Condition::Greater,
+            Condition::GreaterOrEqual => Condition::Less,
+            Condition::Below => Condition::AboveOrEqual,
+            Condition::Above => Condition::BelowOrEqual,
+            Condition::BelowOrEqual => Condition::Above,
+            Condition::AboveOrEqual => Condition::Below,
+        })
+    }
 }
 
+/// How an operand is encoded and written in assembly, in encoding order
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperandKind {
+    /// `R3`
+    Register,
+    /// `0x41`
+    Const8,
+    /// `label`, a target in the 256-byte page of the next instruction
+    Address8,
+    /// `label`, any target in the instruction region
+    Address16,
+    /// `[R1]`, an 8-bit address held in a register
+    RegisterAddress8,
+    /// `[R1:R2]`, a 16-bit address held in a register pair
+    RegisterAddress16,
+}
+
+impl OperandKind {
+    pub fn encoded_len(&self) -> u8 {
+        match self {
+            OperandKind::RegisterAddress16 | OperandKind::Address16 => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Opcode {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::Exit => "exit",
+            Opcode::MovReg8Const8 => "mov",
+            Opcode::XorMemReg8Const8 => "xorm",
+            Opcode::CmpReg8Const8 => "cmpi",
+            Opcode::JumpIfNotEqual => "jne",
+            Opcode::SubReg8Const8 => "subi",
+            Opcode::AddReg8Const8 => "addi",
+            Opcode::ReadStdinStack => "read",
+            Opcode::PopReg8 => "pop",
+            Opcode::DerefAddressReg16Reg8 => "deref",
+            Opcode::XorReg8Reg8 => "xor",
+            Opcode::WriteStdoutConst8 => "write",
+            Opcode::CmpReg8Reg8 => "cmp",
+            Opcode::XorReg8Const8 => "xori",
+            Opcode::Jump => "jmp",
+            Opcode::JumpIfEqual => "je",
+            Opcode::JumpIfLess => "jl",
+            Opcode::JumpIfGreater => "jg",
+            Opcode::JumpIfLessOrEqual => "jle",
+            Opcode::JumpIfGreaterOrEqual => "jge",
+            Opcode::JumpIfBelow => "jb",
+            Opcode::JumpIfAbove => "ja",
+            Opcode::JumpIfBelowOrEqual => "jbe",
+            Opcode::JumpIfAboveOrEqual => "jae",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::PushReg8 => "push",
+            Opcode::PushConst8 => "pushi",
+            Opcode::StoreAddressReg16Reg8 => "store",
+            Opcode::StoreAddressReg16Const8 => "storei",
+            Opcode::XorAddressReg16Const8 => "xorm16",
+            Opcode::AddAddressReg16Const8 => "addm16",
+            Opcode::JumpFar => "ljmp",
+            Opcode::JumpFarIfEqual => "lje",
+            Opcode::JumpFarIfNotEqual => "ljne",
+            Opcode::JumpFarIfLess => "ljl",
+            Opcode::JumpFarIfGreater => "ljg",
+            Opcode::JumpFarIfLessOrEqual => "ljle",
+            Opcode::JumpFarIfGreaterOrEqual => "ljge",
+            Opcode::JumpFarIfBelow => "ljb",
+            Opcode::JumpFarIfAbove => "lja",
+            Opcode::JumpFarIfBelowOrEqual => "ljbe",
+            Opcode::JumpFarIfAboveOrEqual => "ljae",
+            Opcode::CallFar => "lcall",
+            Opcode::RetFar => "lret",
+            Opcode::Syscall => "syscall",
+        }
+    }
+
+    pub fn operands(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::Exit => &[],
+            Opcode::MovReg8Const8 => &[Register, Const8],
+            Opcode::XorMemReg8Const8 => &[RegisterAddress8, Const8],
+            Opcode::CmpReg8Const8 => &[Register, Const8],
+            Opcode::JumpIfNotEqual => &[Address8],
+            Opcode::SubReg8Const8 => &[Register, Const8],
+            Opcode::AddReg8Const8 => &[Register, Const8],
+            Opcode::ReadStdinStack => &[Const8],
+            Opcode::PopReg8 => &[Register],
+            Opcode::DerefAddressReg16Reg8 => &[RegisterAddress16, Register],
+            Opcode::XorReg8Reg8 => &[Register, Register],
+            Opcode::WriteStdoutConst8 => &[Const8],
+            Opcode::CmpReg8Reg8 => &[Register, Register],
+            Opcode::XorReg8Const8 => &[Register, Const8],
+            Opcode::Jump
+            | Opcode::JumpIfEqual
+            | Opcode::JumpIfLess
+            | Opcode::JumpIfGreater
+            | Opcode::JumpIfLessOrEqual
+            | Opcode::JumpIfGreaterOrEqual
+            | Opcode::JumpIfBelow
+            | Opcode::JumpIfAbove
+            | Opcode::JumpIfBelowOrEqual
+            | Opcode::JumpIfAboveOrEqual
+            | Opcode::Call => &[Address8],
+            Opcode::Ret => &[],
+            Opcode::PushReg8 => &[Register],
+            Opcode::PushConst8 => &[Const8],
+            Opcode::StoreAddressReg16Reg8 => &[RegisterAddress16, Register],
+            Opcode::StoreAddressReg16Const8
+            | Opcode::XorAddressReg16Const8
+            | Opcode::AddAddressReg16Const8 => &[RegisterAddress16, Const8],
+            Opcode::JumpFar
+            | Opcode::JumpFarIfEqual
+            | Opcode::JumpFarIfNotEqual
+            | Opcode::JumpFarIfLess
+            | Opcode::JumpFarIfGreater
+            | Opcode::JumpFarIfLessOrEqual
+            | Opcode::JumpFarIfGreaterOrEqual
+            | Opcode::JumpFarIfBelow
+            | Opcode::JumpFarIfAbove
+            | Opcode::JumpFarIfBelowOrEqual
+            | Opcode::JumpFarIfAboveOrEqual
+            | Opcode::CallFar => &[Address16],
+            Opcode::RetFar | Opcode::Syscall => &[],
+        }
+    }
+
+    /// Branch condition of a jump, None for everything else
+    pub fn condition(&self) -> Option<Condition> {
+        Some(match self {
+            Opcode::Jump => Condition::Always,
+            Opcode::JumpIfEqual => Condition::Equal,
+            Opcode::JumpIfNotEqual => Condition::NotEqual,
+            Opcode::JumpIfLess => Condition::Less,
+            Opcode::JumpIfGreater => Condition::Greater,
+            Opcode::JumpIfLessOrEqual => Condition::LessOrEqual,
+            Opcode::JumpIfGreaterOrEqual => Condition::GreaterOrEqual,
+            Opcode::JumpIfBelow => Condition::Below,
+            Opcode::JumpIfAbove => Condition::Above,
+            Opcode::JumpIfBelowOrEqual => Condition::BelowOrEqual,
+            Opcode::JumpIfAboveOrEqual => Condition::AboveOrEqual,
+            Opcode::JumpFar => Condition::Always,
+            Opcode::JumpFarIfEqual => Condition::Equal,
+            Opcode::JumpFarIfNotEqual => Condition::NotEqual,
+            Opcode::JumpFarIfLess => Condition::Less,
+            Opcode::JumpFarIfGreater => Condition::Greater,
+            Opcode::JumpFarIfLessOrEqual => Condition::LessOrEqual,
+            Opcode::JumpFarIfGreaterOrEqual => Condition::GreaterOrEqual,
+            Opcode::JumpFarIfBelow => Condition::Below,
+            Opcode::JumpFarIfAbove => Condition::Above,
+            Opcode::JumpFarIfBelowOrEqual => Condition::BelowOrEqual,
+            Opcode::JumpFarIfAboveOrEqual => Condition::AboveOrEqual,
+            _ => return None,
+        })
+    }
+
+    /// Whether execution can continue with the next instruction
+    pub fn falls_through(&self) -> bool {
+        !matches!(
+            self,
+            Opcode::Exit | Opcode::Jump | Opcode::Ret | Opcode::JumpFar | Opcode::RetFar
+        )
+    }
+
+    /// Whether the instruction pushes a return address before jumping
+    pub fn is_call(&self) -> bool {
+        matches!(self, Opcode::Call | Opcode::CallFar)
+    }
+
+    /// Encoded length, including the opcode byte
+    pub fn encoded_len(&self) -> u8 {
+        1 + self
+            .operands()
+            .iter()
+            .map(OperandKind::encoded_len)
+            .sum::<u8>()
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        Opcode::iter().find(|opcode| opcode.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+}
+
+#[allow(clippy::len_without_is_empty)]
 pub trait Instruction {
     fn opcode(&self) -> Opcode;
 
@@ -31,7 +301,7 @@ pub trait Instruction {
     where
         Self: Sized;
 
-    fn execute(&self, vm: &mut VM);
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault>;
 
     fn len(&self) -> u8;
 
@@ -39,29 +309,133 @@ pub trait Instruction {
 }
 
 impl dyn Instruction {
-    pub fn parse(next: &mut dyn FnMut() -> u8) -> Box<dyn Instruction> {
-        let opcode_int = next() as usize;
-        match Opcode::from_repr(opcode_int)
-            .expect(format!("Unknown opcode {}", opcode_int).as_str())
-        {
-            Opcode::Exit => Box::new(Exit::decode(next)),
-            Opcode::MovReg8Const8 => Box::new(MovReg8Const8::decode(next)),
-            Opcode::XorMemReg8Const8 => Box::new(XorMemReg8Const8::decode(next)),
-            Opcode::CmpReg8Const8 => Box::new(CmpReg8Const8::decode(next)),
-            Opcode::JumpIfNotEqual => Box::new(JumpIfNotEqual::decode(next)),
-            Opcode::SubReg8Const8 => Box::new(SubReg8Const8::decode(next)),
-            Opcode::AddReg8Const8 => Box::new(AddReg8Const8::decode(next)),
-            Opcode::ReadStdinStack => Box::new(ReadStdinStack::decode(next)),
-            Opcode::PopReg8 => Box::new(PopReg8::decode(next)),
-            Opcode::DerefAddressReg16Reg8 => Box::new(DerefAddressReg16Reg8::decode(next)),
-            Opcode::XorReg8Reg8 => Box::new(XorReg8Reg8::decode(next)),
-            Opcode::WriteStdoutConst8 => Box::new(WriteStdoutConst8::decode(next)),
-            Opcode::CmpReg8Reg8 => Box::new(CmpReg8Reg8::decode(next)),
-            Opcode::XorReg8Const8 => Box::new(XorReg8Const8::decode(next)),
-        }
+    pub fn parse(bytes: &[u8]) -> Result<Box<dyn Instruction>, DecodeError> {
+        Decoded::parse(bytes).map(Decoded::into_boxed)
     }
 }
 
+/// Longest encoding of any instruction
+pub const MAX_INSTRUCTION_LEN: usize = 4;
+
+macro_rules! define_decoded {
+    ($($name:ident),* $(,)?) => {
+        /// A decoded instruction as a closed enum, executed without boxing or dynamic
+        /// dispatch. Each variant holds the instruction type of the same name
+        #[derive(Clone, Copy)]
+        pub enum Decoded {
+            $($name($name),)*
+        }
+
+        impl Decoded {
+            pub fn parse(bytes: &[u8]) -> Result<Self, DecodeError> {
+                let (&opcode_int, operands) =
+                    bytes.split_first().ok_or(DecodeError::Truncated)?;
+                let opcode = Opcode::from_repr(opcode_int as usize)
+                    .ok_or(DecodeError::UnknownOpcode(opcode_int))?;
+                let mut iter = operands.iter();
+                let mut truncated = false;
+                let next = &mut || match iter.next() {
+                    Some(value) => *value,
+                    None => {
+                        truncated = true;
+                        0
+                    }
+                };
+                let decoded = match opcode {
+                    $(Opcode::$name => Decoded::$name($name::decode(next)),)*
+                };
+                if truncated {
+                    return Err(DecodeError::Truncated);
+                }
+                Ok(decoded)
+            }
+
+            pub fn as_instruction(&self) -> &dyn Instruction {
+                match self {
+                    $(Decoded::$name(instruction) => instruction,)*
+                }
+            }
+
+            pub fn into_boxed(self) -> Box<dyn Instruction> {
+                match self {
+                    $(Decoded::$name(instruction) => Box::new(instruction),)*
+                }
+            }
+
+            pub fn opcode(&self) -> Opcode {
+                match self {
+                    $(Decoded::$name(_) => Opcode::$name,)*
+                }
+            }
+
+            pub fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+                match self {
+                    $(Decoded::$name(instruction) => instruction.execute(vm),)*
+                }
+            }
+
+            #[allow(clippy::len_without_is_empty)]
+            pub fn len(&self) -> u8 {
+                self.opcode().encoded_len()
+            }
+
+            pub fn encode(&self) -> Vec<u8> {
+                self.as_instruction().encode()
+            }
+        }
+    };
+}
+
+define_decoded!(
+    Exit,
+    MovReg8Const8,
+    XorMemReg8Const8,
+    CmpReg8Const8,
+    JumpIfNotEqual,
+    SubReg8Const8,
+    AddReg8Const8,
+    ReadStdinStack,
+    PopReg8,
+    DerefAddressReg16Reg8,
+    XorReg8Reg8,
+    WriteStdoutConst8,
+    CmpReg8Reg8,
+    XorReg8Const8,
+    Jump,
+    JumpIfEqual,
+    JumpIfLess,
+    JumpIfGreater,
+    JumpIfLessOrEqual,
+    JumpIfGreaterOrEqual,
+    JumpIfBelow,
+    JumpIfAbove,
+    JumpIfBelowOrEqual,
+    JumpIfAboveOrEqual,
+    Call,
+    Ret,
+    PushReg8,
+    PushConst8,
+    StoreAddressReg16Reg8,
+    StoreAddressReg16Const8,
+    XorAddressReg16Const8,
+    AddAddressReg16Const8,
+    JumpFar,
+    JumpFarIfEqual,
+    JumpFarIfNotEqual,
+    JumpFarIfLess,
+    JumpFarIfGreater,
+    JumpFarIfLessOrEqual,
+    JumpFarIfGreaterOrEqual,
+    JumpFarIfBelow,
+    JumpFarIfAbove,
+    JumpFarIfBelowOrEqual,
+    JumpFarIfAboveOrEqual,
+    CallFar,
+    RetFar,
+    Syscall,
+);
+
+#[derive(Clone, Copy)]
 pub struct MovReg8Const8 {
     pub to: RegisterIndex,
     pub value: u8,
@@ -82,8 +456,9 @@ impl Instruction for MovReg8Const8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        vm.registers[self.to].value = self.value;
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        vm.registers.get_mut(self.to)?.value = self.value;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -91,10 +466,11 @@ impl Instruction for MovReg8Const8 {
     }
 
     fn encode(&self) -> Vec<u8> {
-        vec![self.opcode() as _, self.to.0 as u8, self.value]
+        vec![self.opcode() as _, self.to.0, self.value]
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct XorMemReg8Const8 {
     pub register: RegisterIndex,
     pub value: u8,
@@ -115,9 +491,10 @@ impl Instruction for XorMemReg8Const8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        let address = vm.registers[self.register].value;
-        vm.memory[address as usize] ^= self.value;
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = vm.registers.get(self.register)?.value as u16;
+        let value = vm.read(address)?;
+        vm.write(address, value ^ self.value)
     }
 
     fn len(&self) -> u8 {
@@ -129,6 +506,7 @@ impl Instruction for XorMemReg8Const8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct CmpReg8Const8 {
     pub register: RegisterIndex,
     pub comparand: u8,
@@ -149,9 +527,10 @@ impl Instruction for CmpReg8Const8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        vm.flags
-            .set_equal(vm.registers[self.register].value == self.comparand)
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let (_, flags) = Flags::sub(vm.registers.get(self.register)?.value, self.comparand);
+        vm.flags = flags;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -163,6 +542,7 @@ impl Instruction for CmpReg8Const8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct Exit;
 
 impl Instruction for Exit {
@@ -177,8 +557,9 @@ impl Instruction for Exit {
         Self {}
     }
 
-    fn execute(&self, vm: &mut VM) {
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
         vm.stop = true;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -190,37 +571,114 @@ impl Instruction for Exit {
     }
 }
 
-pub struct JumpIfNotEqual {
-    pub address: u8,
-}
-
-impl Instruction for JumpIfNotEqual {
-    fn opcode(&self) -> Opcode {
-        Opcode::JumpIfNotEqual
-    }
-
-    fn decode(next: &mut dyn FnMut() -> u8) -> Self
-    where
-        Self: Sized,
-    {
-        Self { address: next() }
-    }
+macro_rules! define_jump {
+    ($name:ident) => {
+        #[derive(Clone, Copy)]
+        pub struct $name {
+            pub address: u8,
+        }
 
-    fn execute(&self, vm: &mut VM) {
-        if !vm.flags.equal() {
-            vm.registers[Register::PC].value = self.address;
+        impl Instruction for $name {
+            fn opcode(&self) -> Opcode {
+                Opcode::$name
+            }
+
+            fn decode(next: &mut dyn FnMut() -> u8) -> Self
+            where
+                Self: Sized,
+            {
+                Self { address: next() }
+            }
+
+            fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+                let condition = self.opcode().condition().expect("jumps have a condition");
+                if condition.holds(vm.flags) {
+                    vm.jump_near(self.address);
+                }
+                Ok(())
+            }
+
+            fn len(&self) -> u8 {
+                2
+            }
+
+            fn encode(&self) -> Vec<u8> {
+                vec![self.opcode() as _, self.address]
+            }
         }
-    }
+    };
+}
 
-    fn len(&self) -> u8 {
-        2
-    }
+define_jump!(JumpIfNotEqual);
+define_jump!(Jump);
+define_jump!(JumpIfEqual);
+define_jump!(JumpIfLess);
+define_jump!(JumpIfGreater);
+define_jump!(JumpIfLessOrEqual);
+define_jump!(JumpIfGreaterOrEqual);
+define_jump!(JumpIfBelow);
+define_jump!(JumpIfAbove);
+define_jump!(JumpIfBelowOrEqual);
+define_jump!(JumpIfAboveOrEqual);
+
+macro_rules! define_far_jump {
+    ($name:ident) => {
+        #[derive(Clone, Copy)]
+        pub struct $name {
+            pub address: u16,
+        }
 
-    fn encode(&self) -> Vec<u8> {
-        vec![self.opcode() as _, self.address]
-    }
+        impl Instruction for $name {
+            fn opcode(&self) -> Opcode {
+                Opcode::$name
+            }
+
+            fn decode(next: &mut dyn FnMut() -> u8) -> Self
+            where
+                Self: Sized,
+            {
+                Self {
+                    address: Address16 {
+                        high: next(),
+                        low: next(),
+                    }
+                    .into(),
+                }
+            }
+
+            fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+                let condition = self.opcode().condition().expect("jumps have a condition");
+                if condition.holds(vm.flags) {
+                    vm.jump(self.address)?;
+                }
+                Ok(())
+            }
+
+            fn len(&self) -> u8 {
+                3
+            }
+
+            fn encode(&self) -> Vec<u8> {
+                let address = Address16::from(self.address);
+                vec![self.opcode() as _, address.high, address.low]
+            }
+        }
+    };
 }
 
+define_far_jump!(JumpFar);
+define_far_jump!(JumpFarIfEqual);
+define_far_jump!(JumpFarIfNotEqual);
+define_far_jump!(JumpFarIfLess);
+define_far_jump!(JumpFarIfGreater);
+define_far_jump!(JumpFarIfLessOrEqual);
+define_far_jump!(JumpFarIfGreaterOrEqual);
+define_far_jump!(JumpFarIfBelow);
+define_far_jump!(JumpFarIfAbove);
+define_far_jump!(JumpFarIfBelowOrEqual);
+define_far_jump!(JumpFarIfAboveOrEqual);
+
+#[derive(Clone, Copy)]
 pub struct SubReg8Const8 {
     pub register: RegisterIndex,
     pub value: u8,
@@ -241,8 +699,12 @@ impl Instruction for SubReg8Const8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        vm.registers[self.register].value -= self.value;
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let register = vm.registers.get_mut(self.register)?;
+        let (result, flags) = Flags::sub(register.value, self.value);
+        register.value = result;
+        vm.flags = flags;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -254,6 +716,7 @@ impl Instruction for SubReg8Const8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct AddReg8Const8 {
     pub register: RegisterIndex,
     pub value: u8,
@@ -274,8 +737,12 @@ impl Instruction for AddReg8Const8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        vm.registers[self.register].value += self.value;
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let register = vm.registers.get_mut(self.register)?;
+        let (result, flags) = Flags::add(register.value, self.value);
+        register.value = result;
+        vm.flags = flags;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -287,6 +754,7 @@ impl Instruction for AddReg8Const8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct ReadStdinStack {
     pub count: u8,
 }
@@ -303,17 +771,19 @@ impl Instruction for ReadStdinStack {
         Self { count: next() }
     }
 
-    fn execute(&self, vm: &mut VM) {
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
         let mut bytes = vec![0; self.count as usize];
-        io::stdin().read(&mut bytes).unwrap();
+        let _read = vm.input(&mut bytes)?;
         bytes.reverse();
 
-        let sp = &mut vm.registers[Register::SP];
-        if (sp.value as usize + bytes.len()) > VM::STACK_RANGE.len() {
-            panic!("Stack is full");
+        let sp = vm.registers[Register::SP].value;
+        let top = sp.checked_add(self.count).ok_or(Fault::StackFull)?;
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            let address = vm.layout.stack().start + sp as usize + offset;
+            vm.write(address as u16, byte)?;
         }
-        vm.memory[VM::STACK_RANGE][sp.value as usize..bytes.len()].copy_from_slice(&bytes);
-        sp.value += bytes.len() as u8;
+        vm.registers[Register::SP].value = top;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -325,6 +795,7 @@ impl Instruction for ReadStdinStack {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct PopReg8 {
     pub register: RegisterIndex,
 }
@@ -343,14 +814,10 @@ impl Instruction for PopReg8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        let sp = &mut vm.registers[Register::SP];
-        if sp.value == 0 {
-            panic!("Stack is empty");
-        }
-        sp.value -= 1;
-        let stack = &mut vm.memory[VM::STACK_RANGE];
-        vm.registers[self.register].value = stack[(sp.value) as usize];
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        vm.registers.get(self.register)?;
+        vm.registers[self.register].value = vm.pop()?;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -362,6 +829,7 @@ impl Instruction for PopReg8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct DerefAddressReg16Reg8 {
     pub source: AddressReg16,
     pub destination: RegisterIndex,
@@ -385,9 +853,11 @@ impl Instruction for DerefAddressReg16Reg8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        let address = self.source.eval_vm(&vm);
-        vm.registers[self.destination].value = vm.memory[address as usize];
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = self.source.eval_vm(vm)?;
+        vm.registers.get(self.destination)?;
+        vm.registers[self.destination].value = vm.read(address)?;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -404,6 +874,7 @@ impl Instruction for DerefAddressReg16Reg8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct XorReg8Reg8 {
     pub destination: RegisterIndex,
     pub source: RegisterIndex,
@@ -424,10 +895,11 @@ impl Instruction for XorReg8Reg8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        let source = vm.registers[self.source].value;
-        let destination = &mut vm.registers[self.destination];
-        destination.value = destination.value ^ source;
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let source = vm.registers.get(self.source)?.value;
+        let destination = vm.registers.get_mut(self.destination)?;
+        destination.value ^= source;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -439,6 +911,7 @@ impl Instruction for XorReg8Reg8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct WriteStdoutConst8 {
     pub byte: u8,
 }
@@ -455,8 +928,8 @@ impl Instruction for WriteStdoutConst8 {
         Self { byte: next() }
     }
 
-    fn execute(&self, _vm: &mut VM) {
-        print!("{}", char::from(self.byte));
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        vm.output(&[self.byte])
     }
 
     fn len(&self) -> u8 {
@@ -468,6 +941,7 @@ impl Instruction for WriteStdoutConst8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct CmpReg8Reg8 {
     pub comparand1: RegisterIndex,
     pub comparand2: RegisterIndex,
@@ -488,9 +962,13 @@ impl Instruction for CmpReg8Reg8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        vm.flags
-            .set_equal(vm.registers[self.comparand1].value == vm.registers[self.comparand2].value)
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let (_, flags) = Flags::sub(
+            vm.registers.get(self.comparand1)?.value,
+            vm.registers.get(self.comparand2)?.value,
+        );
+        vm.flags = flags;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -502,6 +980,7 @@ impl Instruction for CmpReg8Reg8 {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct XorReg8Const8 {
     pub register: RegisterIndex,
     pub value: u8,
@@ -522,8 +1001,9 @@ impl Instruction for XorReg8Const8 {
         }
     }
 
-    fn execute(&self, vm: &mut VM) {
-        vm.registers[self.register].value ^= self.value;
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        vm.registers.get_mut(self.register)?.value ^= self.value;
+        Ok(())
     }
 
     fn len(&self) -> u8 {
@@ -534,3 +1014,428 @@ impl Instruction for XorReg8Const8 {
         vec![self.opcode() as _, self.register.0, self.value]
     }
 }
+
+/// Pushes the low byte of the next instruction's address and jumps within its page
+#[derive(Clone, Copy)]
+pub struct Call {
+    pub address: u8,
+}
+
+impl Instruction for Call {
+    fn opcode(&self) -> Opcode {
+        Opcode::Call
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self { address: next() }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        vm.push(vm.pc() as u8)?;
+        vm.jump_near(self.address);
+        Ok(())
+    }
+
+    fn len(&self) -> u8 {
+        2
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.opcode() as _, self.address]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Ret {}
+
+impl Instruction for Ret {
+    fn opcode(&self) -> Opcode {
+        Opcode::Ret
+    }
+
+    fn decode(_next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {}
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = vm.pop()?;
+        vm.jump_near(address);
+        Ok(())
+    }
+
+    fn len(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.opcode() as _]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PushReg8 {
+    pub register: RegisterIndex,
+}
+
+impl Instruction for PushReg8 {
+    fn opcode(&self) -> Opcode {
+        Opcode::PushReg8
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            register: RegisterIndex::from(next()),
+        }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let value = vm.registers.get(self.register)?.value;
+        vm.push(value)
+    }
+
+    fn len(&self) -> u8 {
+        2
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.opcode() as _, self.register.0]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PushConst8 {
+    pub value: u8,
+}
+
+impl Instruction for PushConst8 {
+    fn opcode(&self) -> Opcode {
+        Opcode::PushConst8
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self { value: next() }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        vm.push(self.value)
+    }
+
+    fn len(&self) -> u8 {
+        2
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.opcode() as _, self.value]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct StoreAddressReg16Reg8 {
+    pub destination: AddressReg16,
+    pub source: RegisterIndex,
+}
+
+impl Instruction for StoreAddressReg16Reg8 {
+    fn opcode(&self) -> Opcode {
+        Opcode::StoreAddressReg16Reg8
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            destination: AddressReg16 {
+                high: RegisterIndex::from(next()),
+                low: RegisterIndex::from(next()),
+            },
+            source: RegisterIndex::from(next()),
+        }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = self.destination.eval_vm(vm)?;
+        let value = vm.registers.get(self.source)?.value;
+        vm.write(address, value)
+    }
+
+    fn len(&self) -> u8 {
+        4
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![
+            self.opcode() as _,
+            self.destination.high.0,
+            self.destination.low.0,
+            self.source.0,
+        ]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct StoreAddressReg16Const8 {
+    pub destination: AddressReg16,
+    pub value: u8,
+}
+
+impl Instruction for StoreAddressReg16Const8 {
+    fn opcode(&self) -> Opcode {
+        Opcode::StoreAddressReg16Const8
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            destination: AddressReg16 {
+                high: RegisterIndex::from(next()),
+                low: RegisterIndex::from(next()),
+            },
+            value: next(),
+        }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = self.destination.eval_vm(vm)?;
+        vm.write(address, self.value)
+    }
+
+    fn len(&self) -> u8 {
+        4
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![
+            self.opcode() as _,
+            self.destination.high.0,
+            self.destination.low.0,
+            self.value,
+        ]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct XorAddressReg16Const8 {
+    pub destination: AddressReg16,
+    pub value: u8,
+}
+
+impl Instruction for XorAddressReg16Const8 {
+    fn opcode(&self) -> Opcode {
+        Opcode::XorAddressReg16Const8
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            destination: AddressReg16 {
+                high: RegisterIndex::from(next()),
+                low: RegisterIndex::from(next()),
+            },
+            value: next(),
+        }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = self.destination.eval_vm(vm)?;
+        let value = vm.read(address)?;
+        vm.write(address, value ^ self.value)
+    }
+
+    fn len(&self) -> u8 {
+        4
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![
+            self.opcode() as _,
+            self.destination.high.0,
+            self.destination.low.0,
+            self.value,
+        ]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct AddAddressReg16Const8 {
+    pub destination: AddressReg16,
+    pub value: u8,
+}
+
+impl Instruction for AddAddressReg16Const8 {
+    fn opcode(&self) -> Opcode {
+        Opcode::AddAddressReg16Const8
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            destination: AddressReg16 {
+                high: RegisterIndex::from(next()),
+                low: RegisterIndex::from(next()),
+            },
+            value: next(),
+        }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let address = self.destination.eval_vm(vm)?;
+        let (result, flags) = Flags::add(vm.read(address)?, self.value);
+        vm.write(address, result)?;
+        vm.flags = flags;
+        Ok(())
+    }
+
+    fn len(&self) -> u8 {
+        4
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![
+            self.opcode() as _,
+            self.destination.high.0,
+            self.destination.low.0,
+            self.value,
+        ]
+    }
+}
+
+/// Pushes the full address of the next instruction, high byte first, and jumps
+#[derive(Clone, Copy)]
+pub struct CallFar {
+    pub address: u16,
+}
+
+impl Instruction for CallFar {
+    fn opcode(&self) -> Opcode {
+        Opcode::CallFar
+    }
+
+    fn decode(next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            address: Address16 {
+                high: next(),
+                low: next(),
+            }
+            .into(),
+        }
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let sp = vm.registers[Register::SP].value;
+        let next = Address16::from(vm.pc());
+        vm.push(next.high)?;
+        if let Err(fault) = vm.push(next.low).and_then(|_| vm.jump(self.address)) {
+            vm.registers[Register::SP].value = sp;
+            return Err(fault);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> u8 {
+        3
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let address = Address16::from(self.address);
+        vec![self.opcode() as _, address.high, address.low]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct RetFar {}
+
+impl Instruction for RetFar {
+    fn opcode(&self) -> Opcode {
+        Opcode::RetFar
+    }
+
+    fn decode(_next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {}
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let sp = vm.registers[Register::SP].value;
+        let address = vm
+            .pop()
+            .and_then(|low| {
+                Ok(Address16 {
+                    high: vm.pop()?,
+                    low,
+                })
+            })
+            .and_then(|address| vm.jump(address.into()));
+        if address.is_err() {
+            vm.registers[Register::SP].value = sp;
+        }
+        address
+    }
+
+    fn len(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.opcode() as _]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Syscall {}
+
+impl Instruction for Syscall {
+    fn opcode(&self) -> Opcode {
+        Opcode::Syscall
+    }
+
+    fn decode(_next: &mut dyn FnMut() -> u8) -> Self
+    where
+        Self: Sized,
+    {
+        Self {}
+    }
+
+    fn execute(&self, vm: &mut VM) -> Result<(), Fault> {
+        let number = vm.registers[Register::R0].value;
+        // Handlers get the whole VM, so the table is out of it while one runs
+        let mut syscalls = std::mem::take(&mut vm.syscalls);
+        let result = match syscalls.handler(number) {
+            Some(handler) => handler(vm),
+            None => Err(Fault::UnknownSyscall(number)),
+        };
+        vm.syscalls = syscalls;
+        result
+    }
+
+    fn len(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.opcode() as _]
+    }
+}
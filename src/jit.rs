@@ -0,0 +1,969 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::mem::offset_of;
+use std::ops::Range;
+use std::ptr;
+
+use crate::disassembler::format_instruction;
+use crate::error::VmError;
+use crate::instruction::{Condition, Decoded, OperandKind, MAX_INSTRUCTION_LEN};
+use crate::layout::{MemoryLayout, PcWidth};
+use crate::registers::{Register, RegisterIndex};
+use crate::vm::{Flags, VM};
+
+/*
+x86-64 translation of basic blocks. A block is compiled the first time PC
+reaches it and runs straight from an mmap'd buffer, taking a Context that holds
+the registers, the flags and a pointer to VM::memory. rdi holds the Context and
+rsi the memory for the whole block, rax, rcx, rdx and r8 are scratch.
+
+A block ends at the first jump, call or return, before the first instruction
+it can't translate, or after MAX_BLOCK_INSTRUCTIONS. I/O, syscalls, exit and
+far calls are left to the interpreter. So is any access the block can't make
+itself: loads and stores are checked when they run, and one that would fault,
+reach a device or store into an executable region leaves the block before the
+instruction. The interpreter then runs it, so self-modifying code always goes
+through VM::write.
+
+A block keeps the bytes it was compiled from and is only entered while memory
+still holds them. Addresses rewritten more than MAX_RECOMPILES times are
+interpreted from then on.
+
+With `check` set every block also runs on a scratch VM with the interpreter,
+and any difference in registers, flags, PC or memory is reported. To name the
+instruction that went wrong, the block's prefixes are then compiled and run
+one instruction longer at a time, each compared with the interpreter after as
+many steps.
+ */
+
+/// Instructions in the longest block
+const MAX_BLOCK_INSTRUCTIONS: u32 = 64;
+
+/// Times the code at an address may change before it is left to the interpreter
+const MAX_RECOMPILES: u8 = 8;
+
+const CODE_BUFFER_SIZE: usize = 4 << 20;
+
+/// Room for one block, where `check` runs the prefixes of a block that differed
+const CHECK_BUFFER_SIZE: usize = 64 << 10;
+
+#[repr(C)]
+struct Context {
+    registers: [u8; 16],
+    flags: u8,
+    /// Where execution continues, set by every block exit
+    pc: u16,
+    /// Instructions the block executed, set by every block exit
+    executed: u32,
+    memory: *mut u8,
+    /// x86 RFLAGS for each value of the low four bits of Flags, for conditional jumps
+    rflags: [u64; 16],
+}
+
+const REGISTERS: u8 = offset_of!(Context, registers) as u8;
+const FLAGS: u8 = offset_of!(Context, flags) as u8;
+const PC: u8 = offset_of!(Context, pc) as u8;
+const EXECUTED: u8 = offset_of!(Context, executed) as u8;
+const MEMORY: u8 = offset_of!(Context, memory) as u8;
+const RFLAGS: u32 = offset_of!(Context, rflags) as u32;
+
+impl Context {
+    fn new() -> Self {
+        let mut rflags = [0; 16];
+        for (bits, value) in rflags.iter_mut().enumerate() {
+            let flags = Flags::from_bits(bits as u8);
+            // Bit 1 of RFLAGS is always set
+            *value = 0x2
+                | flags.carry() as u64
+                | (flags.zero() as u64) << 6
+                | (flags.sign() as u64) << 7
+                | (flags.overflow() as u64) << 11;
+        }
+        Self {
+            registers: [0; 16],
+            flags: 0,
+            pc: 0,
+            executed: 0,
+            memory: ptr::null_mut(),
+            rflags,
+        }
+    }
+}
+
+type Entry = unsafe extern "sysv64" fn(*mut Context);
+
+#[derive(Clone)]
+struct Block {
+    entry: Entry,
+    /// The x8 code the block was compiled from
+    code: Vec<u8>,
+    instructions: u32,
+}
+
+#[derive(Clone)]
+enum Slot {
+    Empty,
+    Native(Block),
+    /// The first instruction can't be compiled, interpreted while memory still holds `code`
+    Interpreted(Vec<u8>),
+    /// Rewritten too often to be worth compiling
+    SelfModifying,
+}
+
+/// Counters for what ran where
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JitStats {
+    pub blocks_compiled: u64,
+    pub native_instructions: u64,
+    pub interpreted_instructions: u64,
+}
+
+impl Display for JitStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} blocks compiled, {} instructions native, {} interpreted",
+            self.blocks_compiled, self.native_instructions, self.interpreted_instructions
+        )
+    }
+}
+
+/// A compiled block and the interpreter disagreed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Start of the block
+    pub pc: u16,
+    pub instructions: u32,
+    /// Address of the first instruction after which they differ
+    pub at: u16,
+    /// That instruction in assembler syntax
+    pub instruction: String,
+    pub difference: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JitError {
+    Vm(VmError),
+    Mismatch(Mismatch),
+}
+
+impl Display for JitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JitError::Vm(error) => write!(f, "{}", error),
+            JitError::Mismatch(mismatch) => write!(
+                f,
+                "block at {:04x} ({} instructions) differs from the interpreter after {} at {:04x}: {}",
+                mismatch.pc,
+                mismatch.instructions,
+                mismatch.instruction,
+                mismatch.at,
+                mismatch.difference
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JitError {}
+
+impl From<VmError> for JitError {
+    fn from(error: VmError) -> Self {
+        JitError::Vm(error)
+    }
+}
+
+pub struct Jit {
+    /// Compare every block with the interpreter
+    pub check: bool,
+    pub stats: JitStats,
+    buffer: CodeBuffer,
+    slots: Vec<Slot>,
+    recompiles: Vec<u8>,
+    context: Context,
+    layout: MemoryLayout,
+    /// Addresses loads may use without leaving the block
+    readable: Vec<Range<u32>>,
+    /// Addresses stores may use without leaving the block, executable regions excluded
+    writable: Vec<Range<u32>>,
+    scratch: Option<Scratch>,
+}
+
+/// What `check` compares blocks on
+struct Scratch {
+    /// The VM's state before the block
+    before: VM,
+    interpreter: VM,
+    native: VM,
+    /// Prefixes of a block that differed from the interpreter, None when it couldn't be
+    /// mapped and only whole blocks are compared
+    buffer: Option<CodeBuffer>,
+}
+
+impl Jit {
+    /// A JIT for VMs with this layout, which must not change while it runs them
+    pub fn new(layout: &MemoryLayout) -> io::Result<Self> {
+        let ranges = |allowed: &dyn Fn(&crate::layout::Region) -> bool| {
+            let mut ranges: Vec<Range<u32>> = vec![];
+            let mut regions = layout.regions().iter().collect::<Vec<_>>();
+            regions.sort_by_key(|region| region.start);
+            for region in regions.into_iter().filter(|region| allowed(region)) {
+                let range = region.start as u32..region.range().end as u32;
+                match ranges.last_mut() {
+                    Some(last) if last.end == range.start => last.end = range.end,
+                    _ => ranges.push(range),
+                }
+            }
+            ranges
+        };
+        Ok(Self {
+            check: false,
+            stats: JitStats::default(),
+            buffer: CodeBuffer::new(CODE_BUFFER_SIZE)?,
+            slots: vec![Slot::Empty; 1 << 16],
+            recompiles: vec![0; 1 << 16],
+            context: Context::new(),
+            layout: layout.clone(),
+            readable: ranges(&|region| region.permissions.read),
+            writable: ranges(&|region| region.permissions.write && !region.permissions.execute),
+            scratch: None,
+        })
+    }
+
+    pub fn run(&mut self, vm: &mut VM) -> Result<(), JitError> {
+        while !vm.stop {
+            self.step(vm)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the block at PC, or a single instruction with VM::step where there is none
+    pub fn step(&mut self, vm: &mut VM) -> Result<(), JitError> {
+        // Blocks index memory without bounds checks
+        assert_eq!(
+            vm.memory.len(),
+            self.layout.size(),
+            "the JIT was built for a different layout"
+        );
+        debug_assert!(vm.layout == self.layout);
+        match self.block(vm) {
+            Some(entry) => self.execute(vm, entry),
+            None => self.interpret(vm),
+        }
+    }
+
+    fn interpret(&mut self, vm: &mut VM) -> Result<(), JitError> {
+        vm.step()?;
+        self.stats.interpreted_instructions += 1;
+        Ok(())
+    }
+
+    /// The block to run at PC, None when the interpreter has to take the next step
+    fn block(&mut self, vm: &VM) -> Option<Entry> {
+        // These record every access, which compiled code doesn't do
+        if vm.history.is_some() || vm.access_log.is_some() || vm.write_bitmap.is_some() {
+            return None;
+        }
+        if vm
+            .limits
+            .time
+            .is_some_and(|limit| vm.usage.elapsed() >= limit)
+        {
+            return None;
+        }
+        let pc = vm.pc();
+        let holds =
+            |code: &[u8]| vm.memory.get(pc as usize..pc as usize + code.len()) == Some(code);
+        match &self.slots[pc as usize] {
+            Slot::Native(block) if holds(&block.code) => {}
+            Slot::Interpreted(code) if holds(code) => return None,
+            Slot::SelfModifying => return None,
+            Slot::Empty => self.compile(vm, pc),
+            Slot::Native(_) | Slot::Interpreted(_) => {
+                self.recompiles[pc as usize] += 1;
+                if self.recompiles[pc as usize] > MAX_RECOMPILES {
+                    self.slots[pc as usize] = Slot::SelfModifying;
+                    return None;
+                }
+                self.compile(vm, pc);
+            }
+        }
+        let Slot::Native(block) = &self.slots[pc as usize] else {
+            return None;
+        };
+        let fits = vm
+            .limits
+            .instructions
+            .is_none_or(|limit| vm.usage.instructions + block.instructions as u64 <= limit);
+        fits.then_some(block.entry)
+    }
+
+    fn execute(&mut self, vm: &mut VM, entry: Entry) -> Result<(), JitError> {
+        let pc = vm.pc();
+        if self.check {
+            self.sync_scratch(vm);
+        }
+        vm.usage.started.get_or_insert_with(std::time::Instant::now);
+        let executed = enter(&mut self.context, vm, entry);
+        vm.usage.instructions += executed as u64;
+        self.stats.native_instructions += executed as u64;
+        if self.check && executed > 0 {
+            self.compare(vm, pc, executed)?;
+        }
+        if executed == 0 {
+            // Left before its first instruction, which the interpreter has to run
+            return self.interpret(vm);
+        }
+        Ok(())
+    }
+
+    fn sync_scratch(&mut self, vm: &VM) {
+        let layout = &self.layout;
+        let scratch = self.scratch.get_or_insert_with(|| Scratch {
+            before: VM::new(layout.clone()),
+            interpreter: VM::new(layout.clone()),
+            native: VM::new(layout.clone()),
+            buffer: CodeBuffer::new(CHECK_BUFFER_SIZE).ok(),
+        });
+        reset(&mut scratch.before, vm);
+    }
+
+    /// Runs the block's instructions on the scratch VM and compares the results, then
+    /// looks for the first instruction they differ after
+    fn compare(&mut self, vm: &VM, pc: u16, executed: u32) -> Result<(), JitError> {
+        let mut scratch = self.scratch.take().expect("synced before the block");
+        let result = self.compare_on(&mut scratch, vm, pc, executed);
+        self.scratch = Some(scratch);
+        result
+    }
+
+    fn compare_on(
+        &mut self,
+        scratch: &mut Scratch,
+        vm: &VM,
+        pc: u16,
+        executed: u32,
+    ) -> Result<(), JitError> {
+        reset(&mut scratch.interpreter, &scratch.before);
+        for _ in 0..executed {
+            if scratch.interpreter.step().is_err() {
+                break;
+            }
+        }
+        if difference(vm, &scratch.interpreter).is_none() {
+            return Ok(());
+        }
+        reset(&mut scratch.interpreter, &scratch.before);
+        for count in 1..=executed {
+            let at = scratch.interpreter.pc();
+            let instruction = match scratch.interpreter.fetch() {
+                Ok(instruction) => {
+                    format_instruction(instruction.as_ref(), at, self.layout.pc_width(), &|_| None)
+                }
+                Err(_) => "an undecodable instruction".to_string(),
+            };
+            let mismatch = |difference: String| {
+                JitError::Mismatch(Mismatch {
+                    pc,
+                    instructions: executed,
+                    at,
+                    instruction: instruction.clone(),
+                    difference,
+                })
+            };
+            if let Err(error) = scratch.interpreter.step() {
+                return Err(mismatch(format!("the interpreter stopped with {}", error)));
+            }
+            reset(&mut scratch.native, &scratch.before);
+            let (code, _, _) = self
+                .translate(&scratch.native, pc, count)
+                .expect("compiled before with more instructions");
+            let Some(buffer) = &mut scratch.buffer else {
+                break;
+            };
+            buffer.clear();
+            let Ok(Some(start)) = buffer.append(&code) else {
+                break;
+            };
+            // SAFETY: as in Jit::compile, for a block of `count` instructions
+            let entry = unsafe { std::mem::transmute::<*const u8, Entry>(start) };
+            enter(&mut self.context, &mut scratch.native, entry);
+            if let Some(difference) = difference(&scratch.native, &scratch.interpreter) {
+                return Err(mismatch(difference));
+            }
+        }
+        // Every prefix agreed, report the whole block
+        let difference = difference(vm, &scratch.interpreter).unwrap_or_default();
+        Err(JitError::Mismatch(Mismatch {
+            pc,
+            instructions: executed,
+            at: pc,
+            instruction: "the block".to_string(),
+            difference,
+        }))
+    }
+
+    fn compile(&mut self, vm: &VM, pc: u16) {
+        let Some((code, source, instructions)) = self.translate(vm, pc, MAX_BLOCK_INSTRUCTIONS)
+        else {
+            let end = (pc as usize + MAX_INSTRUCTION_LEN).min(vm.memory.len());
+            self.slots[pc as usize] = Slot::Interpreted(vm.memory[pc as usize..end].to_vec());
+            return;
+        };
+        let start = match self.buffer.append(&code) {
+            Ok(Some(start)) => start,
+            Ok(None) => {
+                // Full, drop every block and start over
+                for slot in &mut self.slots {
+                    if matches!(slot, Slot::Native(_)) {
+                        *slot = Slot::Empty;
+                    }
+                }
+                self.buffer.clear();
+                match self.buffer.append(&code) {
+                    Ok(Some(start)) => start,
+                    _ => return,
+                }
+            }
+            Err(_) => return,
+        };
+        // SAFETY: `start` points at the complete block, which follows the sysv64 ABI
+        let entry = unsafe { std::mem::transmute::<*const u8, Entry>(start) };
+        self.slots[pc as usize] = Slot::Native(Block {
+            entry,
+            code: source,
+            instructions,
+        });
+        self.stats.blocks_compiled += 1;
+    }
+
+    /// Machine code for the block at `pc` of at most `limit` instructions, the x8 code it
+    /// covers and its length in instructions, None when the first instruction can't be compiled
+    fn translate(&self, vm: &VM, pc: u16, limit: u32) -> Option<(Vec<u8>, Vec<u8>, u32)> {
+        let region = self.layout.region_at(pc as usize)?;
+        if !region.permissions.execute {
+            return None;
+        }
+        let end = region.range().end;
+        let max_pc = self.layout.pc_width().max_pc() as usize;
+        let mut emitter = Emitter::default();
+        // mov rsi, [rdi + MEMORY]
+        emitter.bytes(&[0x48, 0x8b, 0x77, MEMORY]);
+        let mut address = pc as usize;
+        let mut count = 0;
+        loop {
+            let mark = emitter.code.len();
+            let translated = Decoded::parse(&vm.memory[address..end])
+                .ok()
+                .filter(|decoded| address + (decoded.len() as usize) <= max_pc)
+                .filter(|_| count < limit)
+                .and_then(|decoded| {
+                    let next = (address + decoded.len() as usize) as u16;
+                    let translated =
+                        self.instruction(&mut emitter, decoded, address as u16, next, count)?;
+                    Some((translated, next))
+                });
+            match translated {
+                Some((translated, next)) => {
+                    count += 1;
+                    address = next as usize;
+                    if let Translated::End = translated {
+                        break;
+                    }
+                }
+                None => {
+                    emitter.code.truncate(mark);
+                    emitter.exit(address as u16, count);
+                    break;
+                }
+            }
+        }
+        (count > 0).then(|| {
+            (
+                emitter.code,
+                vm.memory[pc as usize..address].to_vec(),
+                count,
+            )
+        })
+    }
+
+    /// Register offset in the context, None for registers compiled code leaves alone
+    fn register(&self, index: RegisterIndex) -> Option<u8> {
+        let pc_high = self.layout.pc_width() == PcWidth::Wide && index == Register::PCH;
+        (index.0 < 16 && index != Register::PC && !pc_high).then_some(REGISTERS + index.0)
+    }
+
+    /// Emits one instruction, `index` is its position in the block. None when it can't be
+    /// compiled, the caller drops whatever was emitted
+    fn instruction(
+        &self,
+        emitter: &mut Emitter,
+        decoded: Decoded,
+        pc: u16,
+        next: u16,
+        index: u32,
+    ) -> Option<Translated> {
+        let exit = (pc, index);
+        let translated = match decoded {
+            Decoded::MovReg8Const8(instruction) => {
+                let to = self.register(instruction.to)?;
+                emitter.bytes(&[0xc6, 0x47, to, instruction.value]);
+                Translated::Next
+            }
+            Decoded::AddReg8Const8(instruction) => {
+                let register = self.register(instruction.register)?;
+                emitter.bytes(&[0x80, 0x47, register, instruction.value]);
+                emitter.capture_flags();
+                Translated::Next
+            }
+            Decoded::SubReg8Const8(instruction) => {
+                let register = self.register(instruction.register)?;
+                emitter.bytes(&[0x80, 0x6f, register, instruction.value]);
+                emitter.capture_flags();
+                Translated::Next
+            }
+            Decoded::CmpReg8Const8(instruction) => {
+                let register = self.register(instruction.register)?;
+                emitter.bytes(&[0x80, 0x7f, register, instruction.comparand]);
+                emitter.capture_flags();
+                Translated::Next
+            }
+            Decoded::CmpReg8Reg8(instruction) => {
+                let left = self.register(instruction.comparand1)?;
+                let right = self.register(instruction.comparand2)?;
+                // mov al, [rdi + left]; cmp al, [rdi + right]
+                emitter.bytes(&[0x8a, 0x47, left, 0x3a, 0x47, right]);
+                emitter.capture_flags();
+                Translated::Next
+            }
+            Decoded::XorReg8Reg8(instruction) => {
+                let destination = self.register(instruction.destination)?;
+                let source = self.register(instruction.source)?;
+                // mov al, [rdi + source]; xor [rdi + destination], al
+                emitter.bytes(&[0x8a, 0x47, source, 0x30, 0x47, destination]);
+                Translated::Next
+            }
+            Decoded::XorReg8Const8(instruction) => {
+                let register = self.register(instruction.register)?;
+                emitter.bytes(&[0x80, 0x77, register, instruction.value]);
+                Translated::Next
+            }
+            Decoded::DerefAddressReg16Reg8(instruction) => {
+                let high = self.register(instruction.source.high)?;
+                let low = self.register(instruction.source.low)?;
+                let destination = self.register(instruction.destination)?;
+                emitter.address16(high, low);
+                emitter.check(&self.readable, exit)?;
+                // mov al, [rsi + rcx]; mov [rdi + destination], al
+                emitter.bytes(&[0x8a, 0x04, 0x0e, 0x88, 0x47, destination]);
+                Translated::Next
+            }
+            Decoded::StoreAddressReg16Reg8(instruction) => {
+                let high = self.register(instruction.destination.high)?;
+                let low = self.register(instruction.destination.low)?;
+                let source = self.register(instruction.source)?;
+                emitter.address16(high, low);
+                emitter.check(&self.writable, exit)?;
+                // mov al, [rdi + source]; mov [rsi + rcx], al
+                emitter.bytes(&[0x8a, 0x47, source, 0x88, 0x04, 0x0e]);
+                Translated::Next
+            }
+            Decoded::StoreAddressReg16Const8(instruction) => {
+                let high = self.register(instruction.destination.high)?;
+                let low = self.register(instruction.destination.low)?;
+                emitter.address16(high, low);
+                emitter.check(&self.writable, exit)?;
+                // mov byte [rsi + rcx], value
+                emitter.bytes(&[0xc6, 0x04, 0x0e, instruction.value]);
+                Translated::Next
+            }
+            Decoded::XorAddressReg16Const8(instruction) => {
+                let high = self.register(instruction.destination.high)?;
+                let low = self.register(instruction.destination.low)?;
+                emitter.address16(high, low);
+                emitter.check(&self.readable, exit)?;
+                emitter.check(&self.writable, exit)?;
+                // xor byte [rsi + rcx], value
+                emitter.bytes(&[0x80, 0x34, 0x0e, instruction.value]);
+                Translated::Next
+            }
+            Decoded::AddAddressReg16Const8(instruction) => {
+                let high = self.register(instruction.destination.high)?;
+                let low = self.register(instruction.destination.low)?;
+                emitter.address16(high, low);
+                emitter.check(&self.readable, exit)?;
+                emitter.check(&self.writable, exit)?;
+                // add byte [rsi + rcx], value
+                emitter.bytes(&[0x80, 0x04, 0x0e, instruction.value]);
+                emitter.capture_flags();
+                Translated::Next
+            }
+            Decoded::XorMemReg8Const8(instruction) => {
+                let register = self.register(instruction.register)?;
+                // movzx ecx, byte [rdi + register]
+                emitter.bytes(&[0x0f, 0xb6, 0x4f, register]);
+                emitter.check(&self.readable, exit)?;
+                emitter.check(&self.writable, exit)?;
+                emitter.bytes(&[0x80, 0x34, 0x0e, instruction.value]);
+                Translated::Next
+            }
+            Decoded::PushReg8(instruction) => {
+                let register = self.register(instruction.register)?;
+                self.push(emitter, exit)?;
+                // mov al, [rdi + register]; mov [rsi + rcx], al; inc byte [rdi + SP]
+                emitter.bytes(&[0x8a, 0x47, register, 0x88, 0x04, 0x0e]);
+                emitter.bytes(&[0xfe, 0x47, REGISTERS + Register::SP.0]);
+                Translated::Next
+            }
+            Decoded::PushConst8(instruction) => {
+                self.push(emitter, exit)?;
+                emitter.bytes(&[0xc6, 0x04, 0x0e, instruction.value]);
+                emitter.bytes(&[0xfe, 0x47, REGISTERS + Register::SP.0]);
+                Translated::Next
+            }
+            Decoded::PopReg8(instruction) => {
+                let register = self.register(instruction.register)?;
+                self.pop(emitter, exit)?;
+                // mov [rdi + register], al
+                emitter.bytes(&[0x88, 0x47, register]);
+                Translated::Next
+            }
+            Decoded::Call(instruction) => {
+                self.push(emitter, exit)?;
+                emitter.bytes(&[0xc6, 0x04, 0x0e, next as u8]);
+                emitter.bytes(&[0xfe, 0x47, REGISTERS + Register::SP.0]);
+                emitter.exit(next & 0xff00 | instruction.address as u16, index + 1);
+                Translated::End
+            }
+            Decoded::Ret(_) => {
+                self.pop(emitter, exit)?;
+                // mov word [rdi + PC], page; mov [rdi + PC], al
+                emitter.bytes(&[0x66, 0xc7, 0x47, PC]);
+                emitter.u16(next & 0xff00);
+                emitter.bytes(&[0x88, 0x47, PC]);
+                emitter.executed(index + 1);
+                emitter.bytes(&[0xc3]);
+                Translated::End
+            }
+            _ => {
+                let condition = decoded.opcode().condition()?;
+                let bytes = decoded.encode();
+                let target = match decoded.opcode().operands() {
+                    [OperandKind::Address8] => next & 0xff00 | bytes[1] as u16,
+                    [OperandKind::Address16] => u16::from_be_bytes([bytes[1], bytes[2]]),
+                    _ => return None,
+                };
+                if target > self.layout.pc_width().max_pc() {
+                    // The interpreter raises the fault
+                    return None;
+                }
+                emitter.branch(condition, target, next, index + 1);
+                Translated::End
+            }
+        };
+        Some(translated)
+    }
+
+    /// Leaves the address of the next push in ecx, exits when the stack is full
+    fn push(&self, emitter: &mut Emitter, exit: (u16, u32)) -> Option<()> {
+        let sp = REGISTERS + Register::SP.0;
+        // movzx ecx, byte [rdi + SP]; cmp cl, 0xff
+        emitter.bytes(&[0x0f, 0xb6, 0x4f, sp, 0x80, 0xf9, 0xff]);
+        emitter.exit_if(0x4, exit);
+        emitter.add_ecx(self.layout.stack().start as u32);
+        emitter.check(&self.writable, exit)
+    }
+
+    /// Pops into al, exits when the stack is empty
+    fn pop(&self, emitter: &mut Emitter, exit: (u16, u32)) -> Option<()> {
+        let sp = REGISTERS + Register::SP.0;
+        // movzx ecx, byte [rdi + SP]; test cl, cl
+        emitter.bytes(&[0x0f, 0xb6, 0x4f, sp, 0x84, 0xc9]);
+        emitter.exit_if(0x4, exit);
+        // dec ecx
+        emitter.bytes(&[0xff, 0xc9]);
+        emitter.add_ecx(self.layout.stack().start as u32);
+        emitter.check(&self.readable, exit)?;
+        // mov al, [rsi + rcx]; dec byte [rdi + SP]
+        emitter.bytes(&[0x8a, 0x04, 0x0e, 0xfe, 0x4f, sp]);
+        Some(())
+    }
+}
+
+enum Translated {
+    /// The block goes on with the next instruction
+    Next,
+    /// The instruction left the block
+    End,
+}
+
+#[derive(Default)]
+struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// mov dword [rdi + EXECUTED], count
+    fn executed(&mut self, count: u32) {
+        self.bytes(&[0xc7, 0x47, EXECUTED]);
+        self.u32(count);
+    }
+
+    /// Returns with PC at `pc` after `executed` instructions
+    fn exit(&mut self, pc: u16, executed: u32) {
+        // mov word [rdi + PC], pc
+        self.bytes(&[0x66, 0xc7, 0x47, PC]);
+        self.u16(pc);
+        self.executed(executed);
+        self.bytes(&[0xc3]);
+    }
+
+    /// Jcc with x86 condition code `condition`, returns where the offset goes
+    fn jump_if(&mut self, condition: u8) -> usize {
+        self.bytes(&[0x0f, 0x80 | condition]);
+        self.u32(0);
+        self.code.len() - 4
+    }
+
+    /// Points the jump whose offset is at `at` here
+    fn bind(&mut self, at: usize) {
+        let offset = (self.code.len() - (at + 4)) as u32;
+        self.code[at..at + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    /// Exits at `exit` when x86 condition `condition` holds
+    fn exit_if(&mut self, condition: u8, (pc, executed): (u16, u32)) {
+        // Jump over the exit when it doesn't
+        let skip = self.jump_if(condition ^ 1);
+        self.exit(pc, executed);
+        self.bind(skip);
+    }
+
+    /// ecx = [Rhigh:Rlow]
+    fn address16(&mut self, high: u8, low: u8) {
+        // movzx ecx, byte [rdi + high]; shl ecx, 8; mov cl, [rdi + low]
+        self.bytes(&[0x0f, 0xb6, 0x4f, high, 0xc1, 0xe1, 0x08, 0x8a, 0x4f, low]);
+    }
+
+    fn add_ecx(&mut self, value: u32) {
+        self.bytes(&[0x81, 0xc1]);
+        self.u32(value);
+    }
+
+    /// Exits at `exit` unless ecx is in one of `ranges`, None when it never is
+    fn check(&mut self, ranges: &[Range<u32>], (pc, executed): (u16, u32)) -> Option<()> {
+        if ranges.is_empty() {
+            return None;
+        }
+        let mut inside = vec![];
+        for range in ranges {
+            // lea eax, [rcx - start]; cmp eax, len; jb inside
+            self.bytes(&[0x8d, 0x81]);
+            self.u32(range.start.wrapping_neg());
+            self.bytes(&[0x3d]);
+            self.u32(range.end - range.start);
+            inside.push(self.jump_if(0x2));
+        }
+        self.exit(pc, executed);
+        for at in inside {
+            self.bind(at);
+        }
+        Some(())
+    }
+
+    /// Stores ZF, CF, SF and OF of the last operation as Flags
+    fn capture_flags(&mut self) {
+        // setz al; setc cl; sets dl; seto r8b
+        self.bytes(&[
+            0x0f, 0x94, 0xc0, 0x0f, 0x92, 0xc1, 0x0f, 0x98, 0xc2, 0x41, 0x0f, 0x90, 0xc0,
+        ]);
+        // shl cl, 1; shl dl, 2; shl r8b, 3
+        self.bytes(&[0xd0, 0xe1, 0xc0, 0xe2, 0x02, 0x41, 0xc0, 0xe0, 0x03]);
+        // or al, cl; or al, dl; or al, r8b; mov [rdi + FLAGS], al
+        self.bytes(&[0x08, 0xc8, 0x08, 0xd0, 0x44, 0x08, 0xc0, 0x88, 0x47, FLAGS]);
+    }
+
+    /// Exits to `target` when `condition` holds on the stored flags, to `next` otherwise
+    fn branch(&mut self, condition: Condition, target: u16, next: u16, executed: u32) {
+        let code = match condition {
+            Condition::Always => {
+                self.exit(target, executed);
+                return;
+            }
+            Condition::Equal => 0x4,
+            Condition::NotEqual => 0x5,
+            Condition::Less => 0xc,
+            Condition::Greater => 0xf,
+            Condition::LessOrEqual => 0xe,
+            Condition::GreaterOrEqual => 0xd,
+            Condition::Below => 0x2,
+            Condition::Above => 0x7,
+            Condition::BelowOrEqual => 0x6,
+            Condition::AboveOrEqual => 0x3,
+        };
+        // movzx eax, byte [rdi + FLAGS]; and eax, 15; push [rdi + RFLAGS + rax * 8]; popfq
+        self.bytes(&[0x0f, 0xb6, 0x47, FLAGS, 0x83, 0xe0, 0x0f, 0xff, 0xb4, 0xc7]);
+        self.u32(RFLAGS);
+        self.bytes(&[0x9d]);
+        let taken = self.jump_if(code);
+        self.exit(next, executed);
+        self.bind(taken);
+        self.exit(target, executed);
+    }
+}
+
+/// Executable memory the blocks are copied into, writable only while copying
+/// Runs the block at `entry` on `vm`'s registers, flags and memory and moves PC to where
+/// it left, returns how many instructions it executed
+fn enter(context: &mut Context, vm: &mut VM, entry: Entry) -> u32 {
+    for (slot, register) in context.registers.iter_mut().zip(&vm.registers.registers) {
+        *slot = register.value;
+    }
+    context.flags = vm.flags.into_bits();
+    context.memory = vm.memory.as_mut_ptr();
+    // SAFETY: the block was compiled for this layout and memory still holds the code it
+    // was compiled from. It only touches the context and memory addresses inside the
+    // layout, which VM::memory covers
+    unsafe { entry(context) };
+    for (register, &value) in vm.registers.registers.iter_mut().zip(&context.registers) {
+        register.value = value;
+    }
+    vm.flags = Flags::from_bits(context.flags);
+    vm.set_pc(context.pc);
+    context.executed
+}
+
+/// Puts `from`'s memory, registers and flags into a scratch VM
+fn reset(to: &mut VM, from: &VM) {
+    to.memory.copy_from_slice(&from.memory);
+    to.decode_cache.clear();
+    to.registers.registers = from.registers.registers;
+    to.flags = from.flags;
+    to.stop = false;
+}
+
+/// The first difference in PC, registers, flags or memory between a VM that ran
+/// compiled code and one that ran the interpreter
+fn difference(native: &VM, interpreted: &VM) -> Option<String> {
+    if native.pc() != interpreted.pc() {
+        return Some(format!(
+            "PC is {:04x}, the interpreter has {:04x}",
+            native.pc(),
+            interpreted.pc()
+        ));
+    }
+    let registers = native
+        .registers
+        .registers
+        .iter()
+        .zip(&interpreted.registers.registers);
+    for (index, (native, interpreted)) in registers.enumerate() {
+        if native.value != interpreted.value {
+            return Some(format!(
+                "{} is {:02x}, the interpreter has {:02x}",
+                RegisterIndex(index as u8),
+                native.value,
+                interpreted.value
+            ));
+        }
+    }
+    if native.flags != interpreted.flags {
+        return Some(format!(
+            "flags are {:02x}, the interpreter has {:02x}",
+            native.flags.into_bits(),
+            interpreted.flags.into_bits()
+        ));
+    }
+    native
+        .memory
+        .iter()
+        .zip(&interpreted.memory)
+        .enumerate()
+        .find(|(_, (native, interpreted))| native != interpreted)
+        .map(|(address, (native, interpreted))| {
+            format!(
+                "[{:04x}] is {:02x}, the interpreter has {:02x}",
+                address, native, interpreted
+            )
+        })
+}
+
+struct CodeBuffer {
+    base: *mut u8,
+    capacity: usize,
+    used: usize,
+}
+
+impl CodeBuffer {
+    fn new(capacity: usize) -> io::Result<Self> {
+        // SAFETY: a fresh anonymous mapping, no existing memory is affected
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                capacity,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            base: base as *mut u8,
+            capacity,
+            used: 0,
+        })
+    }
+
+    fn protect(&mut self, protection: libc::c_int) -> io::Result<()> {
+        // SAFETY: the whole mapping made in CodeBuffer::new
+        let result = unsafe { libc::mprotect(self.base as *mut _, self.capacity, protection) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Copies `code` in and returns where it starts, None when it doesn't fit
+    fn append(&mut self, code: &[u8]) -> io::Result<Option<*const u8>> {
+        if self.used + code.len() > self.capacity {
+            return Ok(None);
+        }
+        self.protect(libc::PROT_READ | libc::PROT_WRITE)?;
+        // SAFETY: in bounds of the mapping, which is writable until the next protect
+        let start = unsafe {
+            let start = self.base.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+            start
+        };
+        self.protect(libc::PROT_READ | libc::PROT_EXEC)?;
+        self.used += code.len();
+        Ok(Some(start))
+    }
+
+    fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        // SAFETY: the mapping made in CodeBuffer::new, no block is run after the JIT is gone
+        unsafe {
+            libc::munmap(self.base as *mut _, self.capacity);
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::assembler::parse_number;
+use crate::error::Fault;
+use crate::vm::AccessKind;
+
+/*
+Memory layout of the VM. Regions are contiguous from address 0, in the order
+instructions, memory, stack, and a flat image holds all of them back to back.
+PC starts at 0 and is 8 bits wide while the instruction region fits in the
+first 256 bytes, a register pair otherwise. SP is 8 bits, so the stack region
+is at most 256 bytes. Each region carries read, write and execute permissions,
+the VM checks them on every fetch, load and store.
+
+    [0, 0x100) => instructions
+    [0x100, 0x300) => memory
+    [0x300, 0x400) => stack
+ */
+
+/// Size of everything a 16-bit address can reach
+pub const ADDRESS_SPACE: usize = 0x10000;
+
+const MAX_NARROW_INSTRUCTIONS: usize = 0x100;
+const MAX_STACK: usize = 0x100;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RegionKind {
+    Instructions,
+    Memory,
+    Stack,
+}
+
+impl RegionKind {
+    const ALL: [RegionKind; 3] = [
+        RegionKind::Instructions,
+        RegionKind::Memory,
+        RegionKind::Stack,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Instructions => "Instructions",
+            RegionKind::Memory => "Memory",
+            RegionKind::Stack => "Stack",
+        }
+    }
+
+    /// Code is read-only, data and stack can't be executed
+    pub fn default_permissions(&self) -> Permissions {
+        match self {
+            RegionKind::Instructions => Permissions::RX,
+            RegionKind::Memory | RegionKind::Stack => Permissions::RW,
+        }
+    }
+
+    /// Key in layout specs, the same as the assembler section
+    pub fn key(&self) -> &'static str {
+        match self {
+            RegionKind::Instructions => "text",
+            RegionKind::Memory => "data",
+            RegionKind::Stack => "stack",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const RX: Self = Self {
+        read: true,
+        write: false,
+        execute: true,
+    };
+    pub const RW: Self = Self {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const RWX: Self = Self {
+        read: true,
+        write: true,
+        execute: true,
+    };
+
+    pub fn allows(&self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Execute => self.execute,
+        }
+    }
+
+    /// These permissions with `kind` allowed as well
+    pub fn allowing(mut self, kind: AccessKind) -> Self {
+        match kind {
+            AccessKind::Read => self.read = true,
+            AccessKind::Write => self.write = true,
+            AccessKind::Execute => self.execute = true,
+        }
+        self
+    }
+}
+
+/// Any of `r`, `w` and `x` in that order, `-` stands for a missing one
+impl FromStr for Permissions {
+    type Err = LayoutError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut permissions = Self {
+            read: false,
+            write: false,
+            execute: false,
+        };
+        let mut rest = text;
+        for (letter, flag) in [
+            ('r', &mut permissions.read),
+            ('w', &mut permissions.write),
+            ('x', &mut permissions.execute),
+        ] {
+            if let Some(tail) = rest.strip_prefix(letter) {
+                *flag = true;
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('-') {
+                rest = tail;
+            }
+        }
+        if !rest.is_empty() {
+            return Err(LayoutError::Spec(format!(
+                "invalid permissions {}, expected some of rwx",
+                text
+            )));
+        }
+        Ok(permissions)
+    }
+}
+
+impl Display for Permissions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let flag = |set: bool, letter: char| if set { letter } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// `text=rwx`, permissions that replace the default ones of a region
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PermissionOverride {
+    pub kind: RegionKind,
+    pub permissions: Permissions,
+}
+
+impl FromStr for PermissionOverride {
+    type Err = LayoutError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (key, permissions) = text.split_once('=').ok_or_else(|| {
+            LayoutError::Spec(format!("expected <region>=<permissions>, got {}", text))
+        })?;
+        let kind = RegionKind::ALL
+            .into_iter()
+            .find(|kind| kind.key() == key.trim())
+            .ok_or_else(|| {
+                LayoutError::Spec(format!(
+                    "unknown region {}, expected text, data or stack",
+                    key
+                ))
+            })?;
+        Ok(Self {
+            kind,
+            permissions: permissions.trim().parse()?,
+        })
+    }
+}
+
+impl Display for PermissionOverride {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.kind.key(), self.permissions)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub kind: RegionKind,
+    pub start: usize,
+    pub size: usize,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.start + self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.range().contains(&address)
+    }
+}
+
+/// Width of the program counter, which follows from the size of the instruction region
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PcWidth {
+    Narrow,
+    /// PC is the pair [Register::PCH:Register::PC]
+    Wide,
+}
+
+impl PcWidth {
+    /// Highest value the program counter can hold
+    pub fn max_pc(&self) -> u16 {
+        match self {
+            PcWidth::Narrow => u8::MAX as u16,
+            PcWidth::Wide => u16::MAX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Regions must be instructions, memory, stack, one each and in that order
+    Order,
+    /// A region does not start where the previous one ends
+    Gap {
+        name: String,
+        start: usize,
+    },
+    Empty(RegionKind),
+    StackTooLarge(usize),
+    TooLarge(usize),
+    Spec(String),
+}
+
+impl Display for LayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutError::Order => write!(f, "expected text, data and stack regions in that order"),
+            LayoutError::Gap { name, start } => {
+                write!(f, "region {} does not start at {:#x}", name, start)
+            }
+            LayoutError::Empty(kind) => write!(f, "{} region is empty", kind.key()),
+            LayoutError::StackTooLarge(size) => write!(
+                f,
+                "stack of {:#x} bytes is larger than SP can address ({:#x})",
+                size, MAX_STACK
+            ),
+            LayoutError::TooLarge(size) => write!(
+                f,
+                "layout of {:#x} bytes is larger than the address space ({:#x})",
+                size, ADDRESS_SPACE
+            ),
+            LayoutError::Spec(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryLayout {
+    regions: Vec<Region>,
+}
+
+impl MemoryLayout {
+    pub fn new(regions: Vec<Region>) -> Result<Self, LayoutError> {
+        let kinds = regions.iter().map(|region| region.kind).collect::<Vec<_>>();
+        if kinds != RegionKind::ALL {
+            return Err(LayoutError::Order);
+        }
+        let mut end = 0;
+        for region in &regions {
+            if region.start != end {
+                return Err(LayoutError::Gap {
+                    name: region.name.clone(),
+                    start: end,
+                });
+            }
+            if region.size == 0 {
+                return Err(LayoutError::Empty(region.kind));
+            }
+            end = region.range().end;
+        }
+        let stack = regions[2].size;
+        if stack > MAX_STACK {
+            return Err(LayoutError::StackTooLarge(stack));
+        }
+        if end > ADDRESS_SPACE {
+            return Err(LayoutError::TooLarge(end));
+        }
+        Ok(Self { regions })
+    }
+
+    /// Regions with their default names and permissions, placed back to back from 0
+    pub fn with_sizes(
+        instructions: usize,
+        memory: usize,
+        stack: usize,
+    ) -> Result<Self, LayoutError> {
+        let mut start = 0;
+        let regions = RegionKind::ALL
+            .into_iter()
+            .zip([instructions, memory, stack])
+            .map(|(kind, size)| {
+                let region = Region {
+                    name: kind.name().to_string(),
+                    kind,
+                    start,
+                    size,
+                    permissions: kind.default_permissions(),
+                };
+                start += size;
+                region
+            })
+            .collect();
+        Self::new(regions)
+    }
+
+    /// The original 0x400 byte layout with an 8-bit PC
+    pub fn narrow() -> Self {
+        Self::with_sizes(0x100, 0x200, 0x100).expect("valid layout")
+    }
+
+    /// 0x1000 bytes of code behind a 16-bit PC
+    pub fn wide() -> Self {
+        Self::with_sizes(0x1000, 0x200, 0x100).expect("valid layout")
+    }
+
+    /// The built-in layout whose flat image is `len` bytes long
+    pub fn for_image_len(len: usize) -> Option<Self> {
+        [Self::narrow(), Self::wide()]
+            .into_iter()
+            .find(|layout| layout.size() == len)
+    }
+
+    pub fn set_permissions(&mut self, kind: RegionKind, permissions: Permissions) {
+        let index = RegionKind::ALL.iter().position(|&other| other == kind);
+        self.regions[index.expect("every kind has a region")].permissions = permissions;
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region(&self, kind: RegionKind) -> &Region {
+        let index = RegionKind::ALL.iter().position(|&other| other == kind);
+        &self.regions[index.expect("every kind has a region")]
+    }
+
+    pub fn region_at(&self, address: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    /// Faults unless `address` is mapped and its region permits `kind`
+    pub fn check(&self, address: u16, kind: AccessKind) -> Result<(), Fault> {
+        let region = self
+            .region_at(address as usize)
+            .ok_or(Fault::AddressOutOfBounds(address))?;
+        if !region.permissions.allows(kind) {
+            return Err(Fault::AccessViolation {
+                address,
+                kind,
+                allowed_by: PermissionOverride {
+                    kind: region.kind,
+                    permissions: region.permissions.allowing(kind),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    pub fn instructions(&self) -> &Region {
+        self.region(RegionKind::Instructions)
+    }
+
+    pub fn memory(&self) -> &Region {
+        self.region(RegionKind::Memory)
+    }
+
+    pub fn stack(&self) -> &Region {
+        self.region(RegionKind::Stack)
+    }
+
+    /// Sizes of the instruction, memory and stack regions
+    pub fn sizes(&self) -> [usize; 3] {
+        [self.instructions(), self.memory(), self.stack()].map(|region| region.size)
+    }
+
+    /// Size of a flat image, which is the whole mapped address space
+    pub fn size(&self) -> usize {
+        self.stack().range().end
+    }
+
+    pub fn pc_width(&self) -> PcWidth {
+        if self.instructions().size <= MAX_NARROW_INSTRUCTIONS {
+            PcWidth::Narrow
+        } else {
+            PcWidth::Wide
+        }
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self::narrow()
+    }
+}
+
+/// `text=0x100,data=0x200,stack=0x100`, region sizes in order
+impl FromStr for MemoryLayout {
+    type Err = LayoutError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let spec = |message: String| LayoutError::Spec(message);
+        let parts = text.split(',').collect::<Vec<_>>();
+        if parts.len() != RegionKind::ALL.len() {
+            return Err(spec(format!(
+                "expected text=<size>,data=<size>,stack=<size>, got {}",
+                text
+            )));
+        }
+        let mut sizes = vec![];
+        for (part, kind) in parts.iter().zip(RegionKind::ALL) {
+            let (key, size) = part
+                .split_once('=')
+                .ok_or_else(|| spec(format!("expected {}=<size>, got {}", kind.key(), part)))?;
+            if key.trim() != kind.key() {
+                return Err(LayoutError::Order);
+            }
+            let size = parse_number(size.trim())
+                .and_then(|size| usize::try_from(size).ok())
+                .ok_or_else(|| spec(format!("invalid size {}", size)))?;
+            sizes.push(size);
+        }
+        Self::with_sizes(sizes[0], sizes[1], sizes[2])
+    }
+}
+
+impl Display for MemoryLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let parts = self
+            .regions
+            .iter()
+            .map(|region| format!("{}={:#x}", region.kind.key(), region.size))
+            .collect::<Vec<_>>();
+        write!(f, "{}", parts.join(","))
+    }
+}
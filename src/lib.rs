@@ -0,0 +1,22 @@
+pub mod assembler;
+pub mod bus;
+pub mod cfg;
+pub mod console;
+pub mod debugger;
+pub mod disassembler;
+pub mod error;
+pub mod gdb;
+pub mod history;
+pub mod instruction;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub mod jit;
+pub mod layout;
+pub mod limits;
+pub mod registers;
+pub mod snapshot;
+pub mod symbolic;
+pub mod syscall;
+pub mod taint;
+pub mod trace;
+pub mod unpack;
+pub mod vm;
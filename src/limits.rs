@@ -0,0 +1,107 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/*
+Caps on what a run may use, for images that can't be trusted to exit. Every
+limit is off unless set. Instructions and time are checked before each step,
+input and output before the bytes are transferred, so a run that hits one
+stops with the VM as it was at that point.
+ */
+
+/// Elapsed time is sampled once every this many instructions
+const TIME_CHECK_INTERVAL: u64 = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub instructions: Option<u64>,
+    pub input: Option<u64>,
+    pub output: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+/// The limit a run hit, with its configured value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Instructions(u64),
+    Input(u64),
+    Output(u64),
+    Time(Duration),
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Instructions(limit) => write!(f, "instruction limit of {}", limit),
+            Limit::Input(limit) => write!(f, "input limit of {} bytes", limit),
+            Limit::Output(limit) => write!(f, "output limit of {} bytes", limit),
+            Limit::Time(limit) => write!(f, "time limit of {:?}", limit),
+        }
+    }
+}
+
+/// What a run used so far, counted whether or not limits are set
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    pub instructions: u64,
+    pub input: u64,
+    pub output: u64,
+    pub started: Option<Instant>,
+}
+
+impl Usage {
+    pub fn elapsed(&self) -> Duration {
+        self.started
+            .map_or(Duration::ZERO, |started| started.elapsed())
+    }
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} instructions, {} bytes in, {} bytes out, {:?}",
+            self.instructions,
+            self.input,
+            self.output,
+            self.elapsed()
+        )
+    }
+}
+
+impl Limits {
+    /// Checked before the next instruction runs
+    pub fn check_step(&self, usage: &Usage) -> Result<(), Limit> {
+        if let Some(limit) = self.instructions {
+            if usage.instructions >= limit {
+                return Err(Limit::Instructions(limit));
+            }
+        }
+        if let Some(limit) = self.time {
+            if usage.instructions.is_multiple_of(TIME_CHECK_INTERVAL) && usage.elapsed() >= limit {
+                return Err(Limit::Time(limit));
+            }
+        }
+        Ok(())
+    }
+
+    /// How many of `len` requested input bytes may still be read, an error once none
+    /// may and some are requested
+    pub fn input_budget(&self, usage: &Usage, len: usize) -> Result<usize, Limit> {
+        let Some(limit) = self.input else {
+            return Ok(len);
+        };
+        let remaining = limit.saturating_sub(usage.input);
+        if remaining == 0 && len > 0 {
+            return Err(Limit::Input(limit));
+        }
+        Ok(len.min(remaining.try_into().unwrap_or(usize::MAX)))
+    }
+
+    /// Checked before `len` more output bytes are written
+    pub fn check_output(&self, usage: &Usage, len: usize) -> Result<(), Limit> {
+        match self.output {
+            Some(limit) if usage.output + len as u64 > limit => Err(Limit::Output(limit)),
+            _ => Ok(()),
+        }
+    }
+}
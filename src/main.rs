@@ -1,22 +1,41 @@
+use std::collections::BTreeSet;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::process::ExitCode;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use rand::RngCore;
 
-use crate::instruction::*;
-use crate::registers::{Register, RegisterSet};
-use crate::vm::{Address16, AddressReg16, Flags, VM};
-
-mod instruction;
-mod registers;
-mod vm;
+use x8::bus::{DeviceSpec, RngDevice};
+use x8::cfg::{self, Cfg};
+use x8::console::Streams;
+use x8::debugger::Debugger;
+use x8::error::VmError;
+use x8::gdb::GdbStub;
+use x8::instruction::*;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use x8::jit::{Jit, JitError};
+use x8::layout::{MemoryLayout, PermissionOverride, Permissions, RegionKind};
+use x8::limits::Limits;
+use x8::registers::Register;
+use x8::snapshot::{Snapshot, SnapshotPoint};
+use x8::symbolic::Explorer;
+use x8::taint::Taint;
+use x8::trace::{self, TraceFilter, TraceFormat, Tracer};
+use x8::vm::{Address16, AddressReg16, VM};
+use x8::{assembler, disassembler, unpack};
 
 pub const FLAG_INNER_LEN: usize = 32;
 pub const FLAG_LEN: usize = FLAG_INNER_LEN + "TFCCTF{}".len();
+/// Exit status when a run hits one of the --max-* limits, as timeout(1) does
+const LIMIT_EXIT_CODE: u8 = 124;
 
 fn create_challenge() {
+    let layout = MemoryLayout::narrow();
     let printable_xor_stream = |stream: &[u8]| {
         stream
             .iter()
@@ -27,7 +46,7 @@ fn create_challenge() {
 
     let mut flag = [0; FLAG_INNER_LEN / 2];
     rand::thread_rng().fill_bytes(&mut flag);
-    let flag = hex::encode(&flag);
+    let flag = hex::encode(flag);
     let flag = format!("TFCCTF{{{}}}", flag);
     println!("Generated flag: {flag}");
 
@@ -70,11 +89,11 @@ fn create_challenge() {
         }),
         Box::new(MovReg8Const8 {
             to: Register::R1,
-            value: Address16::from(VM::MEMORY_RANGE.start as u16).high,
+            value: Address16::from(layout.memory().start as u16).high,
         }),
         Box::new(MovReg8Const8 {
             to: Register::R2,
-            value: Address16::from(VM::MEMORY_RANGE.start as u16).low,
+            value: Address16::from(layout.memory().start as u16).low,
         }),
         Box::new(PopReg8 {
             register: Register::R3,
@@ -170,57 +189,536 @@ fn create_challenge() {
     ];
     let plain_instructions = plain_instructions
         .iter()
-        .map(|item| item.encode())
-        .flatten()
+        .flat_map(|item| item.encode())
         .collect::<Vec<_>>();
     let xor_instructions = xor_instructions
         .iter()
-        .map(|item| item.encode())
-        .flatten()
+        .flat_map(|item| item.encode())
         .map(|byte| byte ^ 0x41)
         .collect::<Vec<_>>();
     let mut instructions = [plain_instructions, xor_instructions].concat();
     assert!(
-        instructions.len() < VM::INSTRUCTIONS_BOUNDARY,
+        instructions.len() < layout.instructions().size,
         "Too many instructions"
     );
-    instructions.resize(VM::INSTRUCTIONS_RANGE.len(), 0);
-    let mut memory = vec![0u8; VM::MEMORY_RANGE.len()];
+    instructions.resize(layout.instructions().size, 0);
+    let mut memory = vec![0u8; layout.memory().size];
     let xor_zip = xor_flag
         .iter()
         .zip(xor_bytes.iter())
-        .map(|(&a, &b)| [a ^ 0x41, b ^ 0x41])
-        .flatten()
+        .flat_map(|(&a, &b)| [a ^ 0x41, b ^ 0x41])
         .collect::<Vec<_>>();
     memory[0..(FLAG_LEN * 2)].copy_from_slice(&xor_zip);
-    let stack = vec![0u8; VM::STACK_RANGE.len()];
+    let stack = vec![0u8; layout.stack().size];
     let program = [instructions, memory, stack].concat();
     let mut file = File::create("program.bin").unwrap();
-    file.write(&program).unwrap();
+    file.write_all(&program).unwrap();
 }
 
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[arg(long, required_unless_present = "load_snapshot")]
+    file: Option<String>,
+
+    /// Serve the GDB remote protocol on this local port instead of running
+    #[arg(long)]
+    gdb: Option<u16>,
+
+    /// Record every executed instruction to this file
     #[arg(long)]
-    file: String,
+    trace: Option<String>,
+
+    /// text or jsonl
+    #[arg(long, default_value = "text")]
+    trace_format: TraceFormat,
+
+    /// Only record instructions whose PC is in <start>-<end>
+    #[arg(long, value_parser = trace::parse_pc_range)]
+    trace_pc: Option<RangeInclusive<u16>>,
+
+    /// Only record instructions with this mnemonic, may be repeated
+    #[arg(long, value_parser = parse_mnemonic)]
+    trace_opcode: Vec<Opcode>,
+
+    /// Report every compare that depends on input, with the input offsets involved
+    #[arg(long, conflicts_with = "trace")]
+    taint: bool,
+
+    /// Feed the program this file instead of stdin
+    #[arg(long)]
+    input: Option<String>,
+
+    /// Write the program's output to this file instead of stdout
+    #[arg(long)]
+    output: Option<String>,
+
+    /// Map a device as console@<address> or rng@<address>, may be repeated
+    #[arg(long)]
+    device: Vec<DeviceSpec>,
+
+    /// Seed of the rng devices and the random syscall
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Stop after this many instructions
+    #[arg(long)]
+    max_instructions: Option<u64>,
+
+    /// Stop before reading more than this many input bytes
+    #[arg(long)]
+    max_input: Option<u64>,
+
+    /// Stop before writing more than this many output bytes
+    #[arg(long)]
+    max_output: Option<u64>,
+
+    /// Stop after running for this many seconds
+    #[arg(long, value_parser = parse_seconds)]
+    max_time: Option<Duration>,
+
+    /// Save a snapshot before the instruction at pc=<address> runs, or once step=<count>
+    /// instructions have run
+    #[arg(long)]
+    save_snapshot_at: Option<SnapshotPoint>,
+
+    /// Where --save-snapshot-at writes the snapshot
+    #[arg(long, default_value = "snapshot.x8s")]
+    snapshot_output: String,
+
+    /// Resume from a snapshot instead of starting a program image, the layout and
+    /// devices' state come from the snapshot
+    #[arg(long, conflicts_with = "file")]
+    load_snapshot: Option<String>,
+
+    /// Run hot blocks as native x86-64 code
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    #[arg(long, conflicts_with_all = ["gdb", "trace", "taint", "save_snapshot_at"])]
+    jit: bool,
+
+    /// Like --jit, also running every compiled block with the interpreter and stopping
+    /// at the first difference
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    #[arg(long, conflicts_with_all = ["gdb", "trace", "taint", "save_snapshot_at"])]
+    jit_check: bool,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args)]
+struct LayoutArgs {
+    /// Region sizes as text=<size>,data=<size>,stack=<size>, by default the built-in
+    /// layout matching the image size
+    #[arg(long, global = true)]
+    layout: Option<MemoryLayout>,
+
+    /// Region permissions as text=rwx, data=rw or stack=rw, may be repeated. Text is r-x,
+    /// data and stack are rw- by default. cfg, unpack and solve make text rwx
+    #[arg(long, global = true)]
+    perm: Vec<PermissionOverride>,
+}
+
+impl LayoutArgs {
+    /// The layout given with --layout, otherwise the built-in one for an image of this
+    /// size, with the --perm overrides applied
+    fn for_image(&self, image: &[u8]) -> MemoryLayout {
+        let mut layout = self
+            .layout
+            .clone()
+            .or_else(|| MemoryLayout::for_image_len(image.len()))
+            .unwrap_or_default();
+        for permission in &self.perm {
+            layout.set_permissions(permission.kind, permission.permissions);
+        }
+        layout
+    }
+
+    /// Like for_image, with a writable text region unless --perm says otherwise. The
+    /// analysis subcommands are for images that decode their own code
+    fn for_self_decoding(&self, image: &[u8]) -> MemoryLayout {
+        let mut layout = self.for_image(image);
+        if !self
+            .perm
+            .iter()
+            .any(|permission| permission.kind == RegionKind::Instructions)
+        {
+            layout.set_permissions(RegionKind::Instructions, Permissions::RWX);
+        }
+        layout
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble x8 source into a flat program image
+    Asm {
+        source: String,
+
+        #[arg(short, long, default_value = "program.bin")]
+        output: String,
+    },
+    /// Print an annotated listing of a program image
+    Disasm {
+        file: String,
+
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Step through a program image interactively
+    Debug { file: String },
+    /// Write the control-flow graph of a program image as Graphviz DOT
+    Cfg {
+        file: String,
+
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Analyze the image as stored instead of emulating its decoding stage first
+        #[arg(long = "static")]
+        no_emulation: bool,
+    },
+    /// Run a self-decoding image until it reaches decoded code and dump that image
+    Unpack {
+        file: String,
+
+        #[arg(short, long, default_value = "unpacked.bin")]
+        output: String,
+    },
+    /// Find stdin input that drives a program image to a PC
+    Solve {
+        file: String,
+
+        /// PC the input should reach, such as the success branch
+        #[arg(long, value_parser = parse_address)]
+        target: u16,
+
+        /// Abandon paths that reach this PC, may be repeated
+        #[arg(long, value_parser = parse_address)]
+        avoid: Vec<u16>,
+
+        /// Print the path constraints next to the input
+        #[arg(short, long)]
+        verbose: bool,
+    },
+}
+
+fn parse_mnemonic(mnemonic: &str) -> Result<Opcode, String> {
+    Opcode::from_mnemonic(mnemonic).ok_or_else(|| format!("unknown mnemonic {}", mnemonic))
+}
+
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+    text.parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds {}", text))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    assembler::parse_number(text)
+        .and_then(|value| u16::try_from(value).ok())
+        .ok_or_else(|| format!("invalid address {}", text))
+}
+
+fn asm(source: &str, output: &str) -> ExitCode {
+    let text = fs::read_to_string(source).expect("Could not read file");
+    match assembler::assemble(&text) {
+        Ok(image) => {
+            fs::write(output, image).expect("Could not write file");
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{}:{}", source, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn disasm(file: &str, output: Option<&str>, layout: &LayoutArgs) -> ExitCode {
+    let image = fs::read(file).expect("Could not read file");
+    match disassembler::disassemble(&image, &layout.for_image(&image)) {
+        Ok(listing) => {
+            match output {
+                Some(output) => fs::write(output, listing).expect("Could not write file"),
+                None => print!("{}", listing),
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{}: {}", file, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn debug(file: &str, layout: &LayoutArgs) -> ExitCode {
+    let image = fs::read(file).expect("Could not read file");
+    let layout = layout.for_image(&image);
+    let mut debugger = match Debugger::new(image, layout) {
+        Ok(debugger) => debugger,
+        Err(error) => {
+            eprintln!("{}: {}", file, error);
+            return ExitCode::FAILURE;
+        }
+    };
+    debugger
+        .repl(&mut |line| io::stdin().read_line(line), &mut io::stdout())
+        .expect("Could not access the terminal");
+    ExitCode::SUCCESS
+}
+
+fn cfg(file: &str, output: Option<&str>, no_emulation: bool, layout: &LayoutArgs) -> ExitCode {
+    let image = fs::read(file).expect("Could not read file");
+    let layout = layout.for_self_decoding(&image);
+    let (memory, written) = if no_emulation {
+        (image, BTreeSet::new())
+    } else {
+        match cfg::emulate(&image, &layout, cfg::DEFAULT_EMULATION_STEPS) {
+            Ok(emulation) => (emulation.memory, emulation.written),
+            Err(error) => {
+                eprintln!("{}: {}", file, error);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    let graph = Cfg::recover(&memory, &layout, 0, written).to_dot();
+    match output {
+        Some(output) => fs::write(output, graph).expect("Could not write file"),
+        None => print!("{}", graph),
+    }
+    ExitCode::SUCCESS
+}
+
+fn unpack(file: &str, output: &str, layout: &LayoutArgs) -> ExitCode {
+    let image = fs::read(file).expect("Could not read file");
+    let layout = layout.for_self_decoding(&image);
+    match unpack::unpack(&image, &layout, unpack::DEFAULT_UNPACK_STEPS) {
+        Ok(unpacked) => {
+            fs::write(output, &unpacked.image).expect("Could not write file");
+            eprintln!("Entry point {:02x}", unpacked.entry);
+            if unpacked.flags_lost {
+                eprintln!("Warning: flags were set at the entry point and are clear in the dump");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{}: {}", file, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn solve(file: &str, target: u16, avoid: Vec<u16>, verbose: bool, layout: &LayoutArgs) -> ExitCode {
+    let image = fs::read(file).expect("Could not read file");
+    let layout = layout.for_self_decoding(&image);
+    let explorer = Explorer {
+        avoid: avoid.into_iter().collect(),
+        ..Explorer::default()
+    };
+    match explorer.find(&image, &layout, target) {
+        Ok(Some(solution)) => {
+            if verbose {
+                for constraint in &solution.constraints {
+                    println!("{}", constraint);
+                }
+            }
+            println!("{}", hex::encode(&solution.input));
+            println!("{}", solution.input.escape_ascii());
+            ExitCode::SUCCESS
+        }
+        Ok(None) => {
+            eprintln!("{}: no input reaches {:02x}", file, target);
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!("{}: {}", file, error);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn main() {
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let layout = &args.layout;
+    match args.command {
+        Some(Command::Asm { source, output }) => return asm(&source, &output),
+        Some(Command::Disasm { file, output }) => return disasm(&file, output.as_deref(), layout),
+        Some(Command::Debug { file }) => return debug(&file, layout),
+        Some(Command::Cfg {
+            file,
+            output,
+            no_emulation,
+        }) => return cfg(&file, output.as_deref(), no_emulation, layout),
+        Some(Command::Unpack { file, output }) => return unpack(&file, &output, layout),
+        Some(Command::Solve {
+            file,
+            target,
+            avoid,
+            verbose,
+        }) => return solve(&file, target, avoid, verbose, layout),
+        None => {}
+    }
     if cfg!(debug_assertions) {
         create_challenge();
     }
-    let args = Args::parse();
-    let stream = fs::read(args.file).expect("Could not read file");
-    let mut vm = VM {
-        memory: [0; VM::VM_BOUNDARY],
-        registers: RegisterSet {
-            registers: [Register { value: 0 }; 16],
-        },
-        flags: Flags::new(),
-        stop: false,
+    let snapshot = match args.load_snapshot.as_ref().map(Snapshot::load).transpose() {
+        Ok(snapshot) => snapshot,
+        Err(error) => {
+            eprintln!("Snapshot error: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let image = args
+        .file
+        .as_ref()
+        .map(|file| fs::read(file).expect("Could not read file"));
+    let vm_layout = match (&snapshot, &image) {
+        (Some(snapshot), _) => snapshot.layout(),
+        (None, Some(image)) => Ok(layout.for_image(image)),
+        (None, None) => unreachable!("--file or --load-snapshot is required"),
+    };
+    let mut vm = match vm_layout {
+        Ok(vm_layout) => VM::new(vm_layout),
+        Err(error) => {
+            eprintln!("Snapshot error: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.input.is_some() || args.output.is_some() {
+        let input: Box<dyn Read> = match &args.input {
+            Some(path) => {
+                let mut file = File::open(path).expect("Could not read input file");
+                // Pick up the input where the snapshotted run left it
+                if let Some(snapshot) = &snapshot {
+                    file.seek(SeekFrom::Start(snapshot.input))
+                        .expect("Could not seek input file");
+                }
+                Box::new(file)
+            }
+            None => Box::new(io::stdin()),
+        };
+        let output: Box<dyn Write> = match &args.output {
+            Some(path) => Box::new(File::create(path).expect("Could not create output file")),
+            None => Box::new(io::stdout()),
+        };
+        vm.console = Box::new(Streams { input, output });
+    }
+    vm.rng = RngDevice::new(args.seed);
+    vm.limits = Limits {
+        instructions: args.max_instructions,
+        input: args.max_input,
+        output: args.max_output,
+        time: args.max_time,
     };
-    vm.run(&stream.to_vec());
+    for spec in &args.device {
+        if let Err(error) = vm.attach(spec.start, spec.device(args.seed)) {
+            eprintln!("VM error: {}", error);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(snapshot) = &snapshot {
+        if let Err(error) = snapshot.restore(&mut vm) {
+            eprintln!("Snapshot error: {}", error);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(image) = &image {
+        if let Err(error) = vm.load(image) {
+            eprintln!("VM error: {}", error);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(port) = args.gdb {
+        GdbStub::new(vm).serve(port).expect("Could not serve gdb");
+        return ExitCode::SUCCESS;
+    }
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    if args.jit || args.jit_check {
+        return run_jit(vm, args.jit_check);
+    }
+    let mut tracer = args.trace.as_ref().map(|path| {
+        let file = File::create(path).expect("Could not create trace file");
+        let filter = TraceFilter {
+            pc: args.trace_pc,
+            opcodes: args.trace_opcode.clone(),
+        };
+        Tracer::new(BufWriter::new(file), args.trace_format, filter)
+    });
+    let mut taint = args.taint.then(Taint::new);
+    let mut save_at = args.save_snapshot_at;
+    let mut result = Ok(());
+    while result.is_ok() && !vm.stop {
+        if save_at.is_some_and(|point| point.reached(&vm)) {
+            save_at = None;
+            Snapshot::capture(&vm)
+                .save(&args.snapshot_output)
+                .expect("Could not write snapshot");
+        }
+        result = match (&mut tracer, &mut taint) {
+            (Some(tracer), _) => tracer.step(&mut vm).expect("Could not write trace"),
+            (None, Some(taint)) => taint.step(&mut vm),
+            (None, None) => vm.step(),
+        };
+    }
+    if let Some(tracer) = &mut tracer {
+        tracer.flush().expect("Could not write trace");
+    }
+    if let Some(taint) = &taint {
+        for compare in &taint.compares {
+            eprintln!("{}", compare);
+        }
+    }
+    if let Some(point) = save_at {
+        eprintln!("Snapshot point {} was never reached", point);
+    }
+    finish(&vm, result)
+}
+
+/// Exit code of a finished run, errors are reported on stderr
+fn finish(vm: &VM, result: Result<(), VmError>) -> ExitCode {
     if cfg!(debug_assertions) {
         println!("{}", vm);
     }
+    match result {
+        Ok(()) => ExitCode::from(vm.status),
+        Err(error) if error.limit().is_some() => {
+            eprintln!("VM stopped: {}", error);
+            eprintln!("Used {}", vm.usage);
+            if !cfg!(debug_assertions) {
+                eprintln!("{}", vm);
+            }
+            ExitCode::from(LIMIT_EXIT_CODE)
+        }
+        Err(error) => {
+            eprintln!("VM error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+fn run_jit(mut vm: VM, check: bool) -> ExitCode {
+    let mut jit = match Jit::new(&vm.layout) {
+        Ok(jit) => jit,
+        Err(error) => {
+            eprintln!("Could not start the JIT: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    jit.check = check;
+    let result = match jit.run(&mut vm) {
+        Ok(()) => Ok(()),
+        Err(JitError::Vm(error)) => Err(error),
+        Err(error @ JitError::Mismatch(_)) => {
+            eprintln!("JIT mismatch: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    if cfg!(debug_assertions) {
+        eprintln!("JIT: {}", jit.stats);
+    }
+    finish(&vm, result)
 }
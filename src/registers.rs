@@ -1,5 +1,8 @@
+use std::fmt::{Display, Formatter};
 use std::ops::{Deref, Index, IndexMut};
 
+use crate::error::Fault;
+
 #[repr(transparent)]
 #[derive(Copy, Clone)]
 pub struct Register {
@@ -7,7 +10,7 @@ pub struct Register {
 }
 
 #[repr(transparent)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct RegisterIndex(pub u8);
 
 impl From<u8> for RegisterIndex {
@@ -16,6 +19,34 @@ impl From<u8> for RegisterIndex {
     }
 }
 
+impl RegisterIndex {
+    /// Parses `R0`-`R7`, `PC`, `SP` and the raw `R<n>` form used for unnamed indices
+    pub fn from_name(name: &str) -> Option<Self> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "PC" => Some(Register::PC),
+            "SP" => Some(Register::SP),
+            _ => {
+                let digits = upper.strip_prefix('R')?;
+                if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return None;
+                }
+                digits.parse().ok().map(RegisterIndex)
+            }
+        }
+    }
+}
+
+impl Display for RegisterIndex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Register::PC => write!(f, "PC"),
+            Register::SP => write!(f, "SP"),
+            RegisterIndex(index) => write!(f, "R{}", index),
+        }
+    }
+}
+
 impl Deref for Register {
     type Target = u8;
 
@@ -49,6 +80,8 @@ impl Register {
     define_register_index!(7);
     define_register!(PC, 8);
     define_register!(SP, 9);
+    // High byte of the program counter in 16-bit PC images, a plain register otherwise
+    define_register!(PCH, 10);
 }
 
 #[repr(transparent)]
@@ -56,6 +89,37 @@ pub struct RegisterSet {
     pub(crate) registers: [Register; 16],
 }
 
+impl RegisterSet {
+    pub fn new() -> Self {
+        Self {
+            registers: [Register { value: 0 }; 16],
+        }
+    }
+
+    pub fn get(&self, index: RegisterIndex) -> Result<&Register, Fault> {
+        self.registers
+            .get(index.0 as usize)
+            .ok_or(Fault::InvalidRegister(index.0))
+    }
+
+    pub fn get_mut(&mut self, index: RegisterIndex) -> Result<&mut Register, Fault> {
+        self.registers
+            .get_mut(index.0 as usize)
+            .ok_or(Fault::InvalidRegister(index.0))
+    }
+
+    pub fn set(&mut self, index: RegisterIndex, value: u8) -> Result<(), Fault> {
+        self.get_mut(index)?.value = value;
+        Ok(())
+    }
+}
+
+impl Default for RegisterSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Index<RegisterIndex> for RegisterSet {
     type Output = Register;
 
@@ -0,0 +1,459 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::{fs, io};
+
+use crate::assembler::parse_number;
+use crate::bus::Device;
+use crate::layout::{LayoutError, MemoryLayout, Permissions, Region, RegionKind};
+use crate::registers::RegisterSet;
+use crate::vm::{DecodeCache, Flags, VM};
+
+/*
+Checkpoint of everything a run depends on: the layout, memory, registers,
+flags, the stop flag and exit status, what the run used so far, the state of
+mapped devices and of the random syscall's generator. The console, syscall
+handlers and limits belong to the host and stay with the VM a snapshot is
+restored into. `usage.input` is the
+input position, a host resuming from the same input skips that many bytes.
+
+Files are little-endian:
+
+    magic "x8snap", version u16
+    regions     u8 count, each: name, kind u8, start u32, size u32, rwx bits u8
+    memory      u32 length, bytes
+    registers   u8 count, bytes
+    flags u8, stop u8, status u8
+    usage       instructions u64, input u64, output u64
+    rng         state (u16 length, bytes)
+    devices     u8 count, each: start u16, name, state (u16 length, bytes)
+
+Names are a u16 length followed by UTF-8 bytes.
+ */
+
+const MAGIC: &[u8; 6] = b"x8snap";
+
+/// Bumped whenever the file format changes, older files are rejected
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceState {
+    pub start: u16,
+    pub name: String,
+    pub state: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub version: u16,
+    pub regions: Vec<Region>,
+    pub memory: Vec<u8>,
+    pub registers: Vec<u8>,
+    pub flags: u8,
+    pub stop: bool,
+    pub status: u8,
+    pub instructions: u64,
+    pub input: u64,
+    pub output: u64,
+    /// What RngDevice::save returned for VM::rng
+    pub rng: Vec<u8>,
+    pub devices: Vec<DeviceState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    Io(io::ErrorKind),
+    NotASnapshot,
+    Version(u16),
+    Truncated,
+    Invalid(String),
+    Layout(LayoutError),
+    /// The snapshot has state for a device the VM has not mapped at the same address
+    MissingDevice {
+        name: String,
+        start: u16,
+    },
+    /// A count or length is larger than its field in the file format
+    TooLarge {
+        field: &'static str,
+        len: usize,
+    },
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(kind) => write!(f, "I/O error: {}", kind),
+            SnapshotError::NotASnapshot => write!(f, "not an x8 snapshot"),
+            SnapshotError::Version(version) => write!(
+                f,
+                "snapshot version {} is not supported, expected {}",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Truncated => write!(f, "truncated snapshot"),
+            SnapshotError::Invalid(message) => write!(f, "invalid snapshot: {}", message),
+            SnapshotError::Layout(error) => write!(f, "invalid snapshot layout: {}", error),
+            SnapshotError::MissingDevice { name, start } => {
+                write!(
+                    f,
+                    "snapshot has a {} device at {:04x} the VM lacks",
+                    name, start
+                )
+            }
+            SnapshotError::TooLarge { field, len } => {
+                write!(f, "{} of {} does not fit in a snapshot", field, len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error.kind())
+    }
+}
+
+impl Snapshot {
+    pub fn capture(vm: &VM) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            regions: vm.layout.regions().to_vec(),
+            memory: vm.memory.clone(),
+            registers: vm
+                .registers
+                .registers
+                .iter()
+                .map(|register| register.value)
+                .collect(),
+            flags: vm.flags.into_bits(),
+            stop: vm.stop,
+            status: vm.status,
+            instructions: vm.usage.instructions,
+            input: vm.usage.input,
+            output: vm.usage.output,
+            rng: vm.rng.save(),
+            devices: vm
+                .bus
+                .devices()
+                .map(|(start, device)| DeviceState {
+                    start,
+                    name: device.name().to_string(),
+                    state: device.save(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The layout the snapshot was taken with, a VM to restore into should be built with it
+    pub fn layout(&self) -> Result<MemoryLayout, SnapshotError> {
+        MemoryLayout::new(self.regions.clone()).map_err(SnapshotError::Layout)
+    }
+
+    /// Puts the captured state into `vm`, which keeps its console, syscalls and limits.
+    /// Every device in the snapshot must be mapped in `vm` at the same address
+    pub fn restore(&self, vm: &mut VM) -> Result<(), SnapshotError> {
+        let layout = self.layout()?;
+        if self.memory.len() != layout.size() {
+            return Err(SnapshotError::Invalid(format!(
+                "{:#x} bytes of memory for a {:#x} byte layout",
+                self.memory.len(),
+                layout.size()
+            )));
+        }
+        let mut registers = RegisterSet::new();
+        if self.registers.len() != registers.registers.len() {
+            return Err(SnapshotError::Invalid(format!(
+                "{} registers",
+                self.registers.len()
+            )));
+        }
+        for (register, &value) in registers.registers.iter_mut().zip(&self.registers) {
+            register.value = value;
+        }
+        let mapped = |saved: &DeviceState| {
+            vm.bus
+                .devices()
+                .any(|(start, device)| start == saved.start && device.name() == saved.name)
+        };
+        if let Some(saved) = self.devices.iter().find(|saved| !mapped(saved)) {
+            return Err(SnapshotError::MissingDevice {
+                name: saved.name.clone(),
+                start: saved.start,
+            });
+        }
+        for (start, device) in vm.bus.devices_mut() {
+            let saved = self
+                .devices
+                .iter()
+                .find(|saved| saved.start == start && saved.name == device.name());
+            if let Some(saved) = saved {
+                device.restore(&saved.state);
+            }
+        }
+        vm.decode_cache = DecodeCache::new(layout.size());
+        vm.layout = layout;
+        vm.memory = self.memory.clone();
+        vm.registers = registers;
+        vm.flags = Flags::from_bits(self.flags);
+        vm.stop = self.stop;
+        vm.status = self.status;
+        vm.usage.instructions = self.instructions;
+        vm.usage.input = self.input;
+        vm.usage.output = self.output;
+        vm.rng.restore(&self.rng);
+        // Undo steps were recorded against the state being replaced
+        if let Some(history) = &mut vm.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        let mut out = Writer(MAGIC.to_vec());
+        out.u16(self.version);
+        out.u8_len("region count", self.regions.len())?;
+        for region in &self.regions {
+            out.string(&region.name)?;
+            out.u8(region.kind as u8);
+            out.u32_len("region start", region.start)?;
+            out.u32_len("region size", region.size)?;
+            let permissions = region.permissions;
+            out.u8(permissions.read as u8
+                | (permissions.write as u8) << 1
+                | (permissions.execute as u8) << 2);
+        }
+        out.u32_len("memory size", self.memory.len())?;
+        out.0.extend(&self.memory);
+        out.u8_len("register count", self.registers.len())?;
+        out.0.extend(&self.registers);
+        out.u8(self.flags);
+        out.u8(self.stop as u8);
+        out.u8(self.status);
+        out.u64(self.instructions);
+        out.u64(self.input);
+        out.u64(self.output);
+        out.u16_len("rng state size", self.rng.len())?;
+        out.0.extend(&self.rng);
+        out.u8_len("device count", self.devices.len())?;
+        for device in &self.devices {
+            out.u16(device.start);
+            out.string(&device.name)?;
+            out.u16_len("device state size", device.state.len())?;
+            out.0.extend(&device.state);
+        }
+        Ok(out.0)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut input = Reader(bytes);
+        if input.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = input.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+        let mut regions = vec![];
+        for _ in 0..input.u8()? {
+            let name = input.string()?;
+            let kind = match input.u8()? {
+                0 => RegionKind::Instructions,
+                1 => RegionKind::Memory,
+                2 => RegionKind::Stack,
+                kind => return Err(SnapshotError::Invalid(format!("region kind {}", kind))),
+            };
+            let start = input.u32()? as usize;
+            let size = input.u32()? as usize;
+            let bits = input.u8()?;
+            regions.push(Region {
+                name,
+                kind,
+                start,
+                size,
+                permissions: Permissions {
+                    read: bits & 1 != 0,
+                    write: bits & 2 != 0,
+                    execute: bits & 4 != 0,
+                },
+            });
+        }
+        let len = input.u32()? as usize;
+        let memory = input.take(len)?.to_vec();
+        let len = input.u8()? as usize;
+        let registers = input.take(len)?.to_vec();
+        let flags = input.u8()?;
+        let stop = input.u8()? != 0;
+        let status = input.u8()?;
+        let instructions = input.u64()?;
+        let input_position = input.u64()?;
+        let output = input.u64()?;
+        let len = input.u16()? as usize;
+        let rng = input.take(len)?.to_vec();
+        let mut devices = vec![];
+        for _ in 0..input.u8()? {
+            let start = input.u16()?;
+            let name = input.string()?;
+            let len = input.u16()? as usize;
+            devices.push(DeviceState {
+                start,
+                name,
+                state: input.take(len)?.to_vec(),
+            });
+        }
+        if !input.0.is_empty() {
+            return Err(SnapshotError::Invalid("trailing bytes".to_string()));
+        }
+        Ok(Self {
+            version,
+            regions,
+            memory,
+            registers,
+            flags,
+            stop,
+            status,
+            instructions,
+            input: input_position,
+            output,
+            rng,
+            devices,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        Ok(fs::write(path, self.to_bytes()?)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+/// When to take a snapshot: `pc=<address>` before the instruction there runs, or
+/// `step=<count>` once that many instructions have run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotPoint {
+    Pc(u16),
+    Step(u64),
+}
+
+impl SnapshotPoint {
+    pub fn reached(&self, vm: &VM) -> bool {
+        match *self {
+            SnapshotPoint::Pc(pc) => vm.pc() == pc,
+            SnapshotPoint::Step(step) => vm.usage.instructions == step,
+        }
+    }
+}
+
+impl Display for SnapshotPoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotPoint::Pc(pc) => write!(f, "pc={:#06x}", pc),
+            SnapshotPoint::Step(step) => write!(f, "step={}", step),
+        }
+    }
+}
+
+impl FromStr for SnapshotPoint {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = text
+            .split_once('=')
+            .ok_or_else(|| format!("expected pc=<address> or step=<count>, got {}", text))?;
+        let number = parse_number(value).ok_or_else(|| format!("invalid number {}", value))?;
+        match kind {
+            "pc" => u16::try_from(number)
+                .map(SnapshotPoint::Pc)
+                .map_err(|_| format!("invalid address {}", value)),
+            "step" => u64::try_from(number)
+                .map(SnapshotPoint::Step)
+                .map_err(|_| format!("invalid count {}", value)),
+            _ => Err(format!(
+                "unknown snapshot point {}, expected pc or step",
+                kind
+            )),
+        }
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u8_len(&mut self, field: &'static str, len: usize) -> Result<(), SnapshotError> {
+        let len = u8::try_from(len).map_err(|_| SnapshotError::TooLarge { field, len })?;
+        self.u8(len);
+        Ok(())
+    }
+
+    fn u16_len(&mut self, field: &'static str, len: usize) -> Result<(), SnapshotError> {
+        let len = u16::try_from(len).map_err(|_| SnapshotError::TooLarge { field, len })?;
+        self.u16(len);
+        Ok(())
+    }
+
+    fn u32_len(&mut self, field: &'static str, len: usize) -> Result<(), SnapshotError> {
+        let len = u32::try_from(len).map_err(|_| SnapshotError::TooLarge { field, len })?;
+        self.u32(len);
+        Ok(())
+    }
+
+    fn string(&mut self, text: &str) -> Result<(), SnapshotError> {
+        self.u16_len("name length", text.len())?;
+        self.0.extend(text.as_bytes());
+        Ok(())
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, SnapshotError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| SnapshotError::Invalid("name is not UTF-8".to_string()))
+    }
+}
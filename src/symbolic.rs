@@ -0,0 +1,797 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use crate::disassembler::jump_targets;
+use crate::error::{DecodeError, Fault, VmError};
+use crate::instruction::{Condition, Instruction, Opcode};
+use crate::layout::{MemoryLayout, PcWidth};
+use crate::registers::{Register, RegisterIndex};
+use crate::syscall::{SYS_EXIT, SYS_PRINT, SYS_READ, SYS_WRITE};
+use crate::vm::{AccessKind, Flags, VM};
+
+/*
+Symbolic interpreter next to VM. Registers and memory cells hold 8-bit
+expressions over the bytes consumed by ReadStdinStack, PC and addresses stay
+concrete. A conditional jump on symbolic Flags forks the state, each side
+carrying its path constraint, and reaching the target PC asks the solver for a
+concrete input.
+ */
+
+/// 8-bit bitvector expression, arithmetic wraps
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Expr {
+    Const(u8),
+    /// Offset into the program's input
+    Input(usize),
+    Xor(Rc<Expr>, Rc<Expr>),
+    Add(Rc<Expr>, Rc<Expr>),
+    Sub(Rc<Expr>, Rc<Expr>),
+}
+
+impl Expr {
+    pub fn constant(&self) -> Option<u8> {
+        match self {
+            Expr::Const(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn xor(left: &Rc<Expr>, right: &Rc<Expr>) -> Rc<Expr> {
+        match (left.as_ref(), right.as_ref()) {
+            (Expr::Const(a), Expr::Const(b)) => Rc::new(Expr::Const(a ^ b)),
+            (_, Expr::Const(0)) => left.clone(),
+            (Expr::Const(0), _) => right.clone(),
+            _ if left == right => Rc::new(Expr::Const(0)),
+            (Expr::Xor(inner, a), Expr::Const(b)) if a.constant().is_some() => {
+                Expr::xor(inner, &Rc::new(Expr::Const(a.constant().unwrap() ^ b)))
+            }
+            (Expr::Const(_), _) => Expr::xor(right, left),
+            _ => Rc::new(Expr::Xor(left.clone(), right.clone())),
+        }
+    }
+
+    pub fn add(left: &Rc<Expr>, right: &Rc<Expr>) -> Rc<Expr> {
+        match (left.as_ref(), right.as_ref()) {
+            (Expr::Const(a), Expr::Const(b)) => Rc::new(Expr::Const(a.wrapping_add(*b))),
+            (_, Expr::Const(0)) => left.clone(),
+            (Expr::Add(inner, a), Expr::Const(b)) if a.constant().is_some() => Expr::add(
+                inner,
+                &Rc::new(Expr::Const(a.constant().unwrap().wrapping_add(*b))),
+            ),
+            (Expr::Const(_), _) => Expr::add(right, left),
+            _ => Rc::new(Expr::Add(left.clone(), right.clone())),
+        }
+    }
+
+    pub fn sub(left: &Rc<Expr>, right: &Rc<Expr>) -> Rc<Expr> {
+        match right.as_ref() {
+            Expr::Const(value) => Expr::add(left, &Rc::new(Expr::Const(value.wrapping_neg()))),
+            _ if left == right => Rc::new(Expr::Const(0)),
+            _ => Rc::new(Expr::Sub(left.clone(), right.clone())),
+        }
+    }
+
+    pub fn eval(&self, input: &dyn Fn(usize) -> u8) -> u8 {
+        match self {
+            Expr::Const(value) => *value,
+            Expr::Input(offset) => input(*offset),
+            Expr::Xor(left, right) => left.eval(input) ^ right.eval(input),
+            Expr::Add(left, right) => left.eval(input).wrapping_add(right.eval(input)),
+            Expr::Sub(left, right) => left.eval(input).wrapping_sub(right.eval(input)),
+        }
+    }
+
+    fn inputs(&self, into: &mut Vec<usize>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Input(offset) => into.push(*offset),
+            Expr::Xor(left, right) | Expr::Add(left, right) | Expr::Sub(left, right) => {
+                left.inputs(into);
+                right.inputs(into);
+            }
+        }
+    }
+
+    /// Solves `self == target` when the only input occurs once, through invertible operations
+    fn invert(&self, target: u8) -> Option<(usize, u8)> {
+        match self {
+            Expr::Const(_) => None,
+            Expr::Input(offset) => Some((*offset, target)),
+            Expr::Xor(left, right) => match (left.constant(), right.constant()) {
+                (_, Some(value)) => left.invert(target ^ value),
+                (Some(value), _) => right.invert(target ^ value),
+                _ => None,
+            },
+            Expr::Add(left, right) => match (left.constant(), right.constant()) {
+                (_, Some(value)) => left.invert(target.wrapping_sub(value)),
+                (Some(value), _) => right.invert(target.wrapping_sub(value)),
+                _ => None,
+            },
+            Expr::Sub(left, right) => match (left.constant(), right.constant()) {
+                (_, Some(value)) => left.invert(target.wrapping_add(value)),
+                (Some(value), _) => right.invert(value.wrapping_sub(target)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Const(value) => write!(f, "{:#04x}", value),
+            Expr::Input(offset) => write!(f, "in[{}]", offset),
+            Expr::Xor(left, right) => write!(f, "({} ^ {})", left, right),
+            Expr::Add(left, right) => write!(f, "({} + {})", left, right),
+            Expr::Sub(left, right) => write!(f, "({} - {})", left, right),
+        }
+    }
+}
+
+/// Flag-setting operation, compares are a subtract
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagOp {
+    Add,
+    Sub,
+}
+
+impl FlagOp {
+    pub fn flags(&self, left: u8, right: u8) -> Flags {
+        match self {
+            FlagOp::Add => Flags::add(left, right).1,
+            FlagOp::Sub => Flags::sub(left, right).1,
+        }
+    }
+}
+
+/// `condition` holds on the flags set by `left op right`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Constraint {
+    pub op: FlagOp,
+    pub left: Rc<Expr>,
+    pub right: Rc<Expr>,
+    pub condition: Condition,
+}
+
+impl Constraint {
+    pub fn holds(&self, input: &dyn Fn(usize) -> u8) -> bool {
+        let flags = self.op.flags(self.left.eval(input), self.right.eval(input));
+        self.condition.holds(flags)
+    }
+
+    fn inputs(&self) -> Vec<usize> {
+        let mut inputs = vec![];
+        self.left.inputs(&mut inputs);
+        self.right.inputs(&mut inputs);
+        inputs.sort_unstable();
+        inputs.dedup();
+        inputs
+    }
+}
+
+impl Display for Constraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let operator = match self.condition {
+            Condition::Always => "true",
+            Condition::Equal => "==",
+            Condition::NotEqual => "!=",
+            Condition::Less => "<s",
+            Condition::Greater => ">s",
+            Condition::LessOrEqual => "<=s",
+            Condition::GreaterOrEqual => ">=s",
+            Condition::Below => "<u",
+            Condition::Above => ">u",
+            Condition::BelowOrEqual => "<=u",
+            Condition::AboveOrEqual => ">=u",
+        };
+        match self.op {
+            FlagOp::Sub => write!(f, "{} {} {}", self.left, operator, self.right),
+            FlagOp::Add => write!(
+                f,
+                "flags({} + {}): {:?}",
+                self.left, self.right, self.condition
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SymbolicError {
+    Image(VmError),
+    /// Code fetched from a cell that holds an input-dependent value
+    SymbolicCode {
+        pc: u16,
+    },
+    /// A memory access whose address depends on input
+    SymbolicAddress {
+        pc: u16,
+    },
+    /// A syscall other than exit, read, write and print
+    UnsupportedSyscall {
+        pc: u16,
+        number: u8,
+    },
+    /// The VM would fault on this path
+    Fault {
+        pc: u16,
+        fault: Fault,
+    },
+    /// Bytes at PC on this path are not an instruction
+    Decode {
+        pc: u16,
+        error: DecodeError,
+    },
+    /// Target not reached within the configured budget
+    BudgetExhausted,
+}
+
+impl Display for SymbolicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymbolicError::Image(error) => write!(f, "{}", error),
+            SymbolicError::SymbolicCode { pc } => {
+                write!(f, "instruction at pc {:02x} depends on input", pc)
+            }
+            SymbolicError::SymbolicAddress { pc } => {
+                write!(f, "memory address at pc {:02x} depends on input", pc)
+            }
+            SymbolicError::UnsupportedSyscall { pc, number } => {
+                write!(f, "syscall {} at pc {:02x} is not supported", number, pc)
+            }
+            SymbolicError::Fault { pc, fault } => write!(f, "{} at pc {:02x}", fault, pc),
+            SymbolicError::Decode { pc, error } => write!(f, "{} at pc {:02x}", error, pc),
+            SymbolicError::BudgetExhausted => write!(f, "exploration budget exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for SymbolicError {}
+
+/// Why a single path stopped, paths that exit are dropped, the rest abort the search
+enum PathEnd {
+    Dead,
+    /// Raised by the instruction at the PC the step started from
+    Fault(Fault),
+    Error(SymbolicError),
+}
+
+#[derive(Clone)]
+pub struct SymbolicState {
+    pub memory: Vec<u8>,
+    pub layout: MemoryLayout,
+    /// Cells whose contents depend on input, shadowing `memory`
+    pub symbolic: HashMap<u16, Rc<Expr>>,
+    pub registers: Vec<Rc<Expr>>,
+    pub flags: SymbolicFlags,
+    pub constraints: Vec<Constraint>,
+    pub input_len: usize,
+    steps: usize,
+}
+
+/// Symbolic view of Flags, kept as the operation that last set them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SymbolicFlags {
+    Concrete(Flags),
+    Op(FlagOp, Rc<Expr>, Rc<Expr>),
+}
+
+impl SymbolicFlags {
+    fn of(op: FlagOp, left: &Rc<Expr>, right: &Rc<Expr>) -> Self {
+        match (left.constant(), right.constant()) {
+            (Some(left), Some(right)) => SymbolicFlags::Concrete(op.flags(left, right)),
+            // x - x sets the same flags whatever x is
+            _ if op == FlagOp::Sub && left == right => {
+                SymbolicFlags::Concrete(FlagOp::Sub.flags(0, 0))
+            }
+            _ => SymbolicFlags::Op(op, left.clone(), right.clone()),
+        }
+    }
+}
+
+impl SymbolicState {
+    pub fn new(image: &[u8], layout: &MemoryLayout) -> Result<Self, VmError> {
+        let mut vm = VM::new(layout.clone());
+        vm.load(image)?;
+        Ok(Self {
+            memory: vm.memory.to_vec(),
+            layout: vm.layout.clone(),
+            symbolic: HashMap::new(),
+            registers: (0..16).map(|_| Rc::new(Expr::Const(0))).collect(),
+            flags: SymbolicFlags::Concrete(Flags::new()),
+            constraints: vec![],
+            input_len: 0,
+            steps: 0,
+        })
+    }
+
+    pub fn pc(&self) -> u16 {
+        let byte = |register: RegisterIndex| {
+            self.registers[register.0 as usize]
+                .constant()
+                .expect("PC is always concrete") as u16
+        };
+        match self.layout.pc_width() {
+            PcWidth::Narrow => byte(Register::PC),
+            PcWidth::Wide => byte(Register::PCH) << 8 | byte(Register::PC),
+        }
+    }
+
+    fn set_pc(&mut self, pc: u16) {
+        self.registers[Register::PC.0 as usize] = Rc::new(Expr::Const(pc as u8));
+        if self.layout.pc_width() == PcWidth::Wide {
+            self.registers[Register::PCH.0 as usize] = Rc::new(Expr::Const((pc >> 8) as u8));
+        }
+    }
+
+    /// Like VM::jump, targets past the PC's reach fault
+    fn jump(&mut self, target: u16) -> Result<(), PathEnd> {
+        if target > self.layout.pc_width().max_pc() {
+            return Err(PathEnd::Fault(Fault::AddressOutOfBounds(target)));
+        }
+        self.set_pc(target);
+        Ok(())
+    }
+
+    fn register(&self, index: u8) -> Result<Rc<Expr>, PathEnd> {
+        self.registers
+            .get(index as usize)
+            .cloned()
+            .ok_or(PathEnd::Fault(Fault::InvalidRegister(index)))
+    }
+
+    fn set_register(&mut self, index: u8, value: Rc<Expr>) -> Result<(), PathEnd> {
+        *self
+            .registers
+            .get_mut(index as usize)
+            .ok_or(PathEnd::Fault(Fault::InvalidRegister(index)))? = value;
+        Ok(())
+    }
+
+    fn concrete_register(&self, index: u8, pc: u16) -> Result<u8, PathEnd> {
+        self.register(index)?
+            .constant()
+            .ok_or(PathEnd::Error(SymbolicError::SymbolicAddress { pc }))
+    }
+
+    /// Accesses the VM would fault on fault here too
+    fn load(&self, address: u16) -> Result<Rc<Expr>, PathEnd> {
+        self.layout
+            .check(address, AccessKind::Read)
+            .map_err(PathEnd::Fault)?;
+        if let Some(value) = self.symbolic.get(&address) {
+            return Ok(value.clone());
+        }
+        Ok(Rc::new(Expr::Const(self.memory[address as usize])))
+    }
+
+    fn store(&mut self, address: u16, value: Rc<Expr>) -> Result<(), PathEnd> {
+        self.layout
+            .check(address, AccessKind::Write)
+            .map_err(PathEnd::Fault)?;
+        let cell = &mut self.memory[address as usize];
+        match value.constant() {
+            Some(value) => {
+                *cell = value;
+                self.symbolic.remove(&address);
+            }
+            None => {
+                self.symbolic.insert(address, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: Rc<Expr>, pc: u16) -> Result<(), PathEnd> {
+        let sp = self.concrete_register(Register::SP.0, pc)?;
+        let top = sp.checked_add(1).ok_or(PathEnd::Fault(Fault::StackFull))?;
+        let address = self.layout.stack().start + sp as usize;
+        self.store(address as u16, value)?;
+        self.registers[Register::SP.0 as usize] = Rc::new(Expr::Const(top));
+        Ok(())
+    }
+
+    fn pop(&mut self, pc: u16) -> Result<Rc<Expr>, PathEnd> {
+        let sp = self.concrete_register(Register::SP.0, pc)?;
+        let top = sp.checked_sub(1).ok_or(PathEnd::Fault(Fault::StackEmpty))?;
+        let address = self.layout.stack().start + top as usize;
+        let value = self.load(address as u16)?;
+        self.registers[Register::SP.0 as usize] = Rc::new(Expr::Const(top));
+        Ok(value)
+    }
+
+    /// Pops a return address that must not depend on input
+    fn pop_concrete(&mut self, pc: u16) -> Result<u8, PathEnd> {
+        self.pop(pc)?
+            .constant()
+            .ok_or(PathEnd::Error(SymbolicError::SymbolicAddress { pc }))
+    }
+
+    fn fetch(&self) -> Result<Box<dyn Instruction>, PathEnd> {
+        let pc = self.pc();
+        self.layout
+            .check(pc, AccessKind::Execute)
+            .map_err(PathEnd::Fault)?;
+        let region = self.layout.region_at(pc as usize).expect("checked above");
+        let bytes = &self.memory[pc as usize..region.range().end];
+        let instruction = <dyn Instruction>::parse(bytes)
+            .map_err(|error| PathEnd::Error(SymbolicError::Decode { pc, error }))?;
+        let symbolic = (pc as usize..pc as usize + instruction.len() as usize)
+            .any(|address| self.symbolic.contains_key(&(address as u16)));
+        if symbolic {
+            return Err(PathEnd::Error(SymbolicError::SymbolicCode { pc }));
+        }
+        Ok(instruction)
+    }
+
+    /// Executes one instruction, returning the successor states
+    fn step(mut self) -> Result<Vec<SymbolicState>, PathEnd> {
+        let pc = self.pc();
+        let instruction = self.fetch()?;
+        let bytes = instruction.encode();
+        let end = pc as usize + instruction.len() as usize;
+        let overflow = end > self.layout.pc_width().max_pc() as usize;
+        let next = (end & self.layout.pc_width().max_pc() as usize) as u16;
+        let page = next & 0xff00;
+        self.set_pc(next);
+        self.steps += 1;
+        let constant = |value: u8| Rc::new(Expr::Const(value));
+        let mut taken = None;
+        match instruction.opcode() {
+            Opcode::Exit => return Err(PathEnd::Dead),
+            Opcode::MovReg8Const8 => self.set_register(bytes[1], constant(bytes[2]))?,
+            Opcode::XorMemReg8Const8 => {
+                let address = self.concrete_register(bytes[1], pc)? as u16;
+                let value = Expr::xor(&self.load(address)?, &constant(bytes[2]));
+                self.store(address, value)?;
+            }
+            Opcode::CmpReg8Const8 => {
+                let value = self.register(bytes[1])?;
+                self.flags = SymbolicFlags::of(FlagOp::Sub, &value, &constant(bytes[2]));
+            }
+            Opcode::CmpReg8Reg8 => {
+                let (left, right) = (self.register(bytes[1])?, self.register(bytes[2])?);
+                self.flags = SymbolicFlags::of(FlagOp::Sub, &left, &right);
+            }
+            Opcode::Jump
+            | Opcode::JumpIfEqual
+            | Opcode::JumpIfNotEqual
+            | Opcode::JumpIfLess
+            | Opcode::JumpIfGreater
+            | Opcode::JumpIfLessOrEqual
+            | Opcode::JumpIfGreaterOrEqual
+            | Opcode::JumpIfBelow
+            | Opcode::JumpIfAbove
+            | Opcode::JumpIfBelowOrEqual
+            | Opcode::JumpIfAboveOrEqual
+            | Opcode::JumpFar
+            | Opcode::JumpFarIfEqual
+            | Opcode::JumpFarIfNotEqual
+            | Opcode::JumpFarIfLess
+            | Opcode::JumpFarIfGreater
+            | Opcode::JumpFarIfLessOrEqual
+            | Opcode::JumpFarIfGreaterOrEqual
+            | Opcode::JumpFarIfBelow
+            | Opcode::JumpFarIfAbove
+            | Opcode::JumpFarIfBelowOrEqual
+            | Opcode::JumpFarIfAboveOrEqual => {
+                let condition = instruction.opcode().condition().unwrap();
+                let target = jump_targets(instruction.as_ref(), pc, self.layout.pc_width())[0];
+                match (&self.flags, condition.negate()) {
+                    (SymbolicFlags::Concrete(flags), _) => {
+                        if condition.holds(*flags) {
+                            self.jump(target)?;
+                        }
+                    }
+                    (SymbolicFlags::Op(..), None) => self.jump(target)?,
+                    (SymbolicFlags::Op(op, left, right), Some(negated)) => {
+                        let constraint = |condition| Constraint {
+                            op: *op,
+                            left: left.clone(),
+                            right: right.clone(),
+                            condition,
+                        };
+                        let (jump, fall) = (constraint(condition), constraint(negated));
+                        let mut fork = self.clone();
+                        fork.constraints.push(jump);
+                        self.constraints.push(fall);
+                        // A taken jump that faults only ends its own path
+                        taken = fork.jump(target).is_ok().then_some(fork);
+                    }
+                }
+            }
+            Opcode::SubReg8Const8 => {
+                let register = self.register(bytes[1])?;
+                self.flags = SymbolicFlags::of(FlagOp::Sub, &register, &constant(bytes[2]));
+                self.set_register(bytes[1], Expr::sub(&register, &constant(bytes[2])))?;
+            }
+            Opcode::AddReg8Const8 => {
+                let register = self.register(bytes[1])?;
+                self.flags = SymbolicFlags::of(FlagOp::Add, &register, &constant(bytes[2]));
+                self.set_register(bytes[1], Expr::add(&register, &constant(bytes[2])))?;
+            }
+            Opcode::ReadStdinStack => {
+                let count = bytes[1];
+                let sp = self.concrete_register(Register::SP.0, pc)?;
+                let top = sp
+                    .checked_add(count)
+                    .ok_or(PathEnd::Fault(Fault::StackFull))?;
+                // Pushed in reverse, so popping yields the input in order
+                for offset in 0..count as usize {
+                    let input = self.input_len + count as usize - 1 - offset;
+                    let address = self.layout.stack().start + sp as usize + offset;
+                    self.store(address as u16, Rc::new(Expr::Input(input)))?;
+                }
+                self.input_len += count as usize;
+                self.registers[Register::SP.0 as usize] = constant(top);
+            }
+            Opcode::PopReg8 => {
+                self.register(bytes[1])?;
+                let value = self.pop(pc)?;
+                self.set_register(bytes[1], value)?;
+            }
+            Opcode::DerefAddressReg16Reg8 => {
+                let high = self.concrete_register(bytes[1], pc)? as u16;
+                let low = self.concrete_register(bytes[2], pc)? as u16;
+                let value = self.load(high << 8 | low)?;
+                self.set_register(bytes[3], value)?;
+            }
+            Opcode::XorReg8Reg8 => {
+                let value = Expr::xor(&self.register(bytes[1])?, &self.register(bytes[2])?);
+                self.set_register(bytes[1], value)?;
+            }
+            Opcode::WriteStdoutConst8 => {}
+            Opcode::XorReg8Const8 => {
+                let value = Expr::xor(&self.register(bytes[1])?, &constant(bytes[2]));
+                self.set_register(bytes[1], value)?;
+            }
+            Opcode::Call => {
+                self.push(constant(next as u8), pc)?;
+                self.set_pc(page | bytes[1] as u16);
+            }
+            Opcode::Ret => {
+                let low = self.pop_concrete(pc)?;
+                self.set_pc(page | low as u16);
+            }
+            Opcode::CallFar => {
+                let [high, low] = next.to_be_bytes();
+                self.push(constant(high), pc)?;
+                self.push(constant(low), pc)?;
+                self.jump(u16::from_be_bytes([bytes[1], bytes[2]]))?;
+            }
+            Opcode::RetFar => {
+                let low = self.pop_concrete(pc)?;
+                let high = self.pop_concrete(pc)?;
+                self.jump(u16::from_be_bytes([high, low]))?;
+            }
+            Opcode::PushReg8 => {
+                let value = self.register(bytes[1])?;
+                self.push(value, pc)?;
+            }
+            Opcode::PushConst8 => self.push(constant(bytes[1]), pc)?,
+            Opcode::StoreAddressReg16Reg8
+            | Opcode::StoreAddressReg16Const8
+            | Opcode::XorAddressReg16Const8
+            | Opcode::AddAddressReg16Const8 => {
+                let high = self.concrete_register(bytes[1], pc)? as u16;
+                let low = self.concrete_register(bytes[2], pc)? as u16;
+                let address = high << 8 | low;
+                let value = match instruction.opcode() {
+                    Opcode::StoreAddressReg16Reg8 => self.register(bytes[3])?,
+                    Opcode::StoreAddressReg16Const8 => constant(bytes[3]),
+                    Opcode::XorAddressReg16Const8 => {
+                        Expr::xor(&self.load(address)?, &constant(bytes[3]))
+                    }
+                    _ => {
+                        let cell = self.load(address)?;
+                        self.flags = SymbolicFlags::of(FlagOp::Add, &cell, &constant(bytes[3]));
+                        Expr::add(&cell, &constant(bytes[3]))
+                    }
+                };
+                self.store(address, value)?;
+            }
+            Opcode::Syscall => {
+                let number = self.concrete_register(Register::R0.0, pc)?;
+                let high = self.concrete_register(Register::R1.0, pc)? as u16;
+                let low = self.concrete_register(Register::R2.0, pc)? as u16;
+                let len = self.concrete_register(Register::R3.0, pc)?;
+                match number {
+                    SYS_EXIT => return Err(PathEnd::Dead),
+                    // Assumes the input has all the bytes asked for
+                    SYS_READ => {
+                        for offset in 0..len as u16 {
+                            let address = (high << 8 | low).checked_add(offset);
+                            let input = Rc::new(Expr::Input(self.input_len + offset as usize));
+                            let address = address.ok_or(PathEnd::Fault(
+                                Fault::AddressOutOfBounds(high << 8 | low),
+                            ))?;
+                            self.store(address, input)?;
+                        }
+                        self.input_len += len as usize;
+                        self.set_register(Register::R0.0, constant(len))?;
+                    }
+                    SYS_WRITE => self.set_register(Register::R0.0, constant(len))?,
+                    SYS_PRINT => {}
+                    _ => {
+                        return Err(PathEnd::Error(SymbolicError::UnsupportedSyscall {
+                            pc,
+                            number,
+                        }))
+                    }
+                }
+            }
+        }
+        let mut successors = vec![self];
+        successors.extend(taken);
+        // Falling through past the last byte of the address space faults
+        successors.retain(|state| !(overflow && state.pc() == next));
+        Ok(successors)
+    }
+}
+
+/// Finds input bytes satisfying every constraint, unconstrained bytes prefer printable values
+pub fn solve(constraints: &[Constraint], input_len: usize) -> Option<Vec<u8>> {
+    let candidates = (0x20..=0x7e)
+        .chain(0..0x20)
+        .chain(0x7f..=0xff)
+        .collect::<Vec<u8>>();
+    let mut domains = vec![candidates; input_len];
+    let mut general = vec![];
+    for constraint in constraints {
+        let inputs = constraint.inputs();
+        match inputs.as_slice() {
+            [] => {
+                if !constraint.holds(&|_| 0) {
+                    return None;
+                }
+            }
+            [offset] => {
+                let domain = domains.get_mut(*offset)?;
+                let inverted = constraint
+                    .right
+                    .constant()
+                    .and_then(|target| constraint.left.invert(target))
+                    .or_else(|| {
+                        let target = constraint.left.constant()?;
+                        constraint.right.invert(target)
+                    });
+                match (inverted, constraint.op, constraint.condition) {
+                    (Some((_, value)), FlagOp::Sub, Condition::Equal) => {
+                        domain.retain(|&candidate| candidate == value)
+                    }
+                    (Some((_, value)), FlagOp::Sub, Condition::NotEqual) => {
+                        domain.retain(|&candidate| candidate != value)
+                    }
+                    _ => domain.retain(|&candidate| constraint.holds(&|_| candidate)),
+                }
+            }
+            _ => general.push((constraint, inputs)),
+        }
+    }
+    if domains.iter().any(Vec::is_empty) {
+        return None;
+    }
+    let mut assignment = vec![None; input_len];
+    let mut budget = SOLVER_BUDGET;
+    if backtrack(&domains, &general, &mut assignment, 0, &mut budget) {
+        Some(assignment.into_iter().map(Option::unwrap).collect())
+    } else {
+        None
+    }
+}
+
+/// Candidate assignments tried before the solver gives up on multi-byte constraints
+const SOLVER_BUDGET: usize = 1 << 24;
+
+fn backtrack(
+    domains: &[Vec<u8>],
+    general: &[(&Constraint, Vec<usize>)],
+    assignment: &mut Vec<Option<u8>>,
+    offset: usize,
+    budget: &mut usize,
+) -> bool {
+    if offset == domains.len() {
+        return true;
+    }
+    for &candidate in &domains[offset] {
+        if *budget == 0 {
+            return false;
+        }
+        *budget -= 1;
+        assignment[offset] = Some(candidate);
+        // Check the constraints this byte completes
+        let consistent = general
+            .iter()
+            .filter(|(_, inputs)| inputs.last() == Some(&offset))
+            .all(|(constraint, _)| constraint.holds(&|input| assignment[input].unwrap()));
+        if consistent && backtrack(domains, general, assignment, offset + 1, budget) {
+            return true;
+        }
+    }
+    assignment[offset] = None;
+    false
+}
+
+pub struct Explorer {
+    pub max_steps: usize,
+    pub max_states: usize,
+    pub avoid: HashSet<u16>,
+}
+
+impl Default for Explorer {
+    fn default() -> Self {
+        Self {
+            max_steps: 1 << 20,
+            max_states: 1 << 16,
+            avoid: HashSet::new(),
+        }
+    }
+}
+
+/// A path that reached the target, with the input that drives the VM down it
+pub struct Solution {
+    pub input: Vec<u8>,
+    pub constraints: Vec<Constraint>,
+}
+
+impl Explorer {
+    /// Depth-first search for a path from the entry point to `target`
+    pub fn find(
+        &self,
+        image: &[u8],
+        layout: &MemoryLayout,
+        target: u16,
+    ) -> Result<Option<Solution>, SymbolicError> {
+        let initial = SymbolicState::new(image, layout).map_err(SymbolicError::Image)?;
+        let mut pending = vec![initial];
+        let mut explored = 0;
+        let mut exhausted = false;
+        while let Some(mut state) = pending.pop() {
+            explored += 1;
+            if explored > self.max_states {
+                return Err(SymbolicError::BudgetExhausted);
+            }
+            loop {
+                let pc = state.pc();
+                if pc == target {
+                    if let Some(input) = solve(&state.constraints, state.input_len) {
+                        return Ok(Some(Solution {
+                            input,
+                            constraints: state.constraints,
+                        }));
+                    }
+                    break;
+                }
+                if self.avoid.contains(&pc) {
+                    break;
+                }
+                if state.steps >= self.max_steps {
+                    exhausted = true;
+                    break;
+                }
+                match state.step() {
+                    Ok(mut successors) => {
+                        // Forks are only worth following when their constraints can be met
+                        successors.retain(feasible);
+                        let Some(first) = successors.pop() else {
+                            break;
+                        };
+                        pending.extend(successors);
+                        state = first;
+                    }
+                    Err(PathEnd::Dead) => break,
+                    Err(PathEnd::Fault(fault)) => return Err(SymbolicError::Fault { pc, fault }),
+                    Err(PathEnd::Error(error)) => return Err(error),
+                }
+            }
+        }
+        if exhausted {
+            return Err(SymbolicError::BudgetExhausted);
+        }
+        Ok(None)
+    }
+}
+
+fn feasible(state: &SymbolicState) -> bool {
+    state.constraints.is_empty() || solve(&state.constraints, state.input_len).is_some()
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use crate::error::Fault;
+use crate::registers::Register;
+use crate::vm::VM;
+
+/*
+Host services behind the `syscall` instruction. R0 selects the handler, R1-R3
+carry its arguments and R0 holds the result where there is one. Buffers are
+the 16-bit address [R1:R2] and the length R3, they go through VM::read and
+VM::write like any other load and store.
+
+    0 exit      stop with status R1
+    1 read      read up to R3 input bytes to [R1:R2], R0 = bytes read
+    2 write     write R3 bytes from [R1:R2], R0 = bytes written
+    3 print     write R1 as a decimal number
+    4 random    fill R3 bytes at [R1:R2] with random bytes from VM::rng
+ */
+
+pub const SYS_EXIT: u8 = 0;
+pub const SYS_READ: u8 = 1;
+pub const SYS_WRITE: u8 = 2;
+pub const SYS_PRINT: u8 = 3;
+pub const SYS_RANDOM: u8 = 4;
+
+pub type Handler = Box<dyn FnMut(&mut VM) -> Result<(), Fault>>;
+
+#[derive(Default)]
+pub struct SyscallTable {
+    handlers: BTreeMap<u8, Handler>,
+}
+
+impl SyscallTable {
+    /// The built-in services
+    pub fn builtin() -> Self {
+        let mut table = Self::default();
+        table.register(SYS_EXIT, |vm| {
+            vm.status = vm.registers[Register::R1].value;
+            vm.stop = true;
+            Ok(())
+        });
+        table.register(SYS_READ, |vm| {
+            let mut bytes = vec![0; vm.registers[Register::R3].value as usize];
+            let read = vm.input(&mut bytes)?;
+            for (address, &byte) in buffer(vm)?.zip(&bytes[..read]) {
+                vm.write(address, byte)?;
+            }
+            vm.registers[Register::R0].value = read as u8;
+            Ok(())
+        });
+        table.register(SYS_WRITE, |vm| {
+            let bytes = buffer(vm)?
+                .map(|address| vm.read(address))
+                .collect::<Result<Vec<_>, _>>()?;
+            vm.output(&bytes)?;
+            vm.registers[Register::R0].value = bytes.len() as u8;
+            Ok(())
+        });
+        table.register(SYS_PRINT, |vm| {
+            let number = vm.registers[Register::R1].value.to_string();
+            vm.output(number.as_bytes())
+        });
+        table.register(SYS_RANDOM, |vm| {
+            for address in buffer(vm)? {
+                let byte = vm.random_byte();
+                vm.write(address, byte)?;
+            }
+            Ok(())
+        });
+        table
+    }
+
+    /// Installs `handler` for `number`, replacing any handler already there
+    pub fn register(
+        &mut self,
+        number: u8,
+        handler: impl FnMut(&mut VM) -> Result<(), Fault> + 'static,
+    ) {
+        self.handlers.insert(number, Box::new(handler));
+    }
+
+    pub fn handler(&mut self, number: u8) -> Option<&mut Handler> {
+        self.handlers.get_mut(&number)
+    }
+}
+
+/// Addresses of the buffer [R1:R2] of length R3, which must fit in the address space
+fn buffer(vm: &VM) -> Result<impl Iterator<Item = u16>, Fault> {
+    let start = u16::from_be_bytes([
+        vm.registers[Register::R1].value,
+        vm.registers[Register::R2].value,
+    ]);
+    let len = vm.registers[Register::R3].value as u16;
+    if len > 0 {
+        start
+            .checked_add(len - 1)
+            .ok_or(Fault::AddressOutOfBounds(start))?;
+    }
+    Ok((0..len).map(move |offset| start + offset))
+}
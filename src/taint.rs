@@ -0,0 +1,233 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+
+use crate::disassembler::format_instruction;
+use crate::error::VmError;
+use crate::instruction::Opcode;
+use crate::layout::ADDRESS_SPACE;
+use crate::registers::Register;
+use crate::syscall::SYS_READ;
+use crate::vm::{AccessKind, MemoryAccess, VM};
+
+/*
+Taint tracking. Every byte pushed by ReadStdinStack is labelled with its
+offset in the input, labels follow data through registers, memory and
+Flags. Only data flow is tracked, a value picked by a tainted address
+or written under a tainted branch stays clean.
+ */
+
+/// Input offsets a value was computed from
+pub type Labels = BTreeSet<usize>;
+
+/// A compare whose outcome depends on input
+#[derive(Clone, Debug)]
+pub struct TaintedCompare {
+    pub step: u64,
+    pub pc: u16,
+    pub instruction: String,
+    pub equal: bool,
+    pub offsets: Labels,
+}
+
+impl Display for TaintedCompare {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let offsets = self
+            .offsets
+            .iter()
+            .map(|offset| offset.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(
+            f,
+            "{:>8} {:02x}: {:<24} equal={} input[{}]",
+            self.step, self.pc, self.instruction, self.equal as u8, offsets
+        )
+    }
+}
+
+pub struct Taint {
+    pub registers: Vec<Labels>,
+    /// One set per address, device ranges included
+    pub memory: Vec<Labels>,
+    pub flags: Labels,
+    /// Input bytes consumed so far
+    pub input_len: usize,
+    pub compares: Vec<TaintedCompare>,
+    steps: u64,
+}
+
+impl Taint {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![Labels::new(); 16],
+            memory: vec![Labels::new(); ADDRESS_SPACE],
+            flags: Labels::new(),
+            input_len: 0,
+            compares: vec![],
+            steps: 0,
+        }
+    }
+
+    fn register(&self, index: u8) -> Labels {
+        self.registers
+            .get(index as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_register(&mut self, index: u8, labels: Labels) {
+        if let Some(register) = self.registers.get_mut(index as usize) {
+            *register = labels;
+        }
+    }
+
+    fn cell(&self, address: u16) -> Labels {
+        self.memory
+            .get(address as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Executes one instruction with VM::step and propagates labels through it
+    pub fn step(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        let pc = vm.pc();
+        let instruction = vm.fetch()?;
+        let syscall = vm.registers[Register::R0].value;
+        let previous_log = vm.access_log.replace(vec![]);
+        let result = vm.step();
+        let accesses = std::mem::replace(&mut vm.access_log, previous_log).unwrap_or_default();
+        let step = self.steps;
+        self.steps += 1;
+        result?;
+
+        let bytes = instruction.encode();
+        let read = |accesses: &[MemoryAccess]| {
+            accesses
+                .iter()
+                .find(|access| access.kind == AccessKind::Read)
+                .map(|access| access.address)
+        };
+        match instruction.opcode() {
+            Opcode::Exit
+            | Opcode::WriteStdoutConst8
+            | Opcode::Jump
+            | Opcode::JumpIfEqual
+            | Opcode::JumpIfNotEqual
+            | Opcode::JumpIfLess
+            | Opcode::JumpIfGreater
+            | Opcode::JumpIfLessOrEqual
+            | Opcode::JumpIfGreaterOrEqual
+            | Opcode::JumpIfBelow
+            | Opcode::JumpIfAbove
+            | Opcode::JumpIfBelowOrEqual
+            | Opcode::JumpIfAboveOrEqual
+            | Opcode::JumpFar
+            | Opcode::JumpFarIfEqual
+            | Opcode::JumpFarIfNotEqual
+            | Opcode::JumpFarIfLess
+            | Opcode::JumpFarIfGreater
+            | Opcode::JumpFarIfLessOrEqual
+            | Opcode::JumpFarIfGreaterOrEqual
+            | Opcode::JumpFarIfBelow
+            | Opcode::JumpFarIfAbove
+            | Opcode::JumpFarIfBelowOrEqual
+            | Opcode::JumpFarIfAboveOrEqual
+            | Opcode::Ret
+            | Opcode::RetFar => {}
+            Opcode::MovReg8Const8 => self.set_register(bytes[1], Labels::new()),
+            // A constant operand leaves the labels where they are
+            Opcode::XorReg8Const8 | Opcode::XorMemReg8Const8 | Opcode::XorAddressReg16Const8 => {}
+            Opcode::SubReg8Const8 | Opcode::AddReg8Const8 => self.flags = self.register(bytes[1]),
+            Opcode::XorReg8Reg8 => {
+                let mut labels = self.register(bytes[1]);
+                labels.extend(self.register(bytes[2]));
+                self.set_register(bytes[1], labels);
+            }
+            Opcode::CmpReg8Const8 | Opcode::CmpReg8Reg8 => {
+                let mut labels = self.register(bytes[1]);
+                if instruction.opcode() == Opcode::CmpReg8Reg8 {
+                    labels.extend(self.register(bytes[2]));
+                }
+                self.flags = labels.clone();
+                if !labels.is_empty() {
+                    self.compares.push(TaintedCompare {
+                        step,
+                        pc,
+                        instruction: format_instruction(
+                            instruction.as_ref(),
+                            pc,
+                            vm.layout.pc_width(),
+                            &|_| None,
+                        ),
+                        equal: vm.flags.zero(),
+                        offsets: labels,
+                    });
+                }
+            }
+            Opcode::ReadStdinStack => {
+                // Bytes are pushed in reverse, the last one written is the first read
+                let count = bytes[1] as usize;
+                for (index, access) in accesses.iter().enumerate() {
+                    let offset = self.input_len + count - 1 - index;
+                    self.memory[access.address as usize] = Labels::from([offset]);
+                }
+                self.input_len += count;
+            }
+            Opcode::PopReg8 => {
+                let labels = read(&accesses).map(|address| self.cell(address));
+                self.set_register(bytes[1], labels.unwrap_or_default());
+            }
+            Opcode::DerefAddressReg16Reg8 => {
+                let labels = read(&accesses).map(|address| self.cell(address));
+                self.set_register(bytes[3], labels.unwrap_or_default());
+            }
+            Opcode::PushReg8 => {
+                let labels = self.register(bytes[1]);
+                for access in &accesses {
+                    self.memory[access.address as usize] = labels.clone();
+                }
+            }
+            Opcode::StoreAddressReg16Reg8 => {
+                let labels = self.register(bytes[3]);
+                for access in &accesses {
+                    self.memory[access.address as usize] = labels.clone();
+                }
+            }
+            Opcode::AddAddressReg16Const8 => {
+                self.flags = read(&accesses)
+                    .map(|address| self.cell(address))
+                    .unwrap_or_default();
+            }
+            Opcode::Syscall => {
+                self.set_register(Register::R0.0, Labels::new());
+                for access in accesses
+                    .iter()
+                    .filter(|access| access.kind == AccessKind::Write)
+                {
+                    self.memory[access.address as usize] = match syscall {
+                        SYS_READ => {
+                            self.input_len += 1;
+                            Labels::from([self.input_len - 1])
+                        }
+                        _ => Labels::new(),
+                    };
+                }
+            }
+            Opcode::PushConst8
+            | Opcode::Call
+            | Opcode::CallFar
+            | Opcode::StoreAddressReg16Const8 => {
+                for access in &accesses {
+                    self.memory[access.address as usize] = Labels::new();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Taint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::assembler::parse_number;
+use crate::disassembler::format_instruction;
+use crate::error::VmError;
+use crate::instruction::Opcode;
+use crate::registers::{Register, RegisterIndex};
+use crate::vm::{MemoryAccess, VM};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "text" => Ok(TraceFormat::Text),
+            "jsonl" => Ok(TraceFormat::JsonLines),
+            _ => Err(format!(
+                "unknown trace format {}, expected text or jsonl",
+                text
+            )),
+        }
+    }
+}
+
+/// Which executed instructions get written out, everything by default
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    pub pc: Option<RangeInclusive<u16>>,
+    pub opcodes: Vec<Opcode>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, opcode: Option<Opcode>) -> bool {
+        let pc_matches = self.pc.as_ref().is_none_or(|range| range.contains(&pc));
+        let opcode_matches =
+            self.opcodes.is_empty() || opcode.is_some_and(|opcode| self.opcodes.contains(&opcode));
+        pc_matches && opcode_matches
+    }
+}
+
+/// Parses `start-end`, both ends inclusive
+pub fn parse_pc_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = text
+        .split_once('-')
+        .ok_or_else(|| format!("expected <start>-<end>, got {}", text))?;
+    let bound = |value: &str| {
+        parse_number(value)
+            .and_then(|value| u16::try_from(value).ok())
+            .ok_or_else(|| format!("invalid address {}", value))
+    };
+    Ok(bound(start)?..=bound(end)?)
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    registers: [u8; 16],
+    flags: u8,
+}
+
+impl State {
+    fn of(vm: &VM) -> Self {
+        Self {
+            registers: vm.registers.registers.map(|register| register.value),
+            flags: vm.flags.into_bits(),
+        }
+    }
+}
+
+pub struct Tracer<W: Write> {
+    output: W,
+    format: TraceFormat,
+    filter: TraceFilter,
+    steps: u64,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W, format: TraceFormat, filter: TraceFilter) -> Self {
+        Self {
+            output,
+            format,
+            filter,
+            steps: 0,
+        }
+    }
+
+    /// Executes one instruction with VM::step and records it
+    pub fn step(&mut self, vm: &mut VM) -> io::Result<Result<(), VmError>> {
+        let pc = vm.pc();
+        let instruction = vm.fetch().ok();
+        let before = State::of(vm);
+        let previous_log = vm.access_log.replace(vec![]);
+        let result = vm.step();
+        let accesses = std::mem::replace(&mut vm.access_log, previous_log).unwrap_or_default();
+        let after = State::of(vm);
+        let step = self.steps;
+        self.steps += 1;
+
+        if !self.filter.matches(
+            pc,
+            instruction.as_ref().map(|instruction| instruction.opcode()),
+        ) {
+            return Ok(result);
+        }
+        let (text, bytes) = match &instruction {
+            Some(instruction) => (
+                format_instruction(instruction.as_ref(), pc, vm.layout.pc_width(), &|_| None),
+                instruction.encode(),
+            ),
+            None => ("(undecodable)".to_string(), vec![]),
+        };
+        let record = Record {
+            step,
+            pc,
+            text,
+            bytes,
+            before,
+            after,
+            accesses,
+            fault: result.as_ref().err(),
+        };
+        match self.format {
+            TraceFormat::Text => record.write_text(&mut self.output)?,
+            TraceFormat::JsonLines => record.write_json(&mut self.output)?,
+        }
+        Ok(result)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+struct Record<'a> {
+    step: u64,
+    pc: u16,
+    text: String,
+    bytes: Vec<u8>,
+    before: State,
+    after: State,
+    accesses: Vec<MemoryAccess>,
+    fault: Option<&'a VmError>,
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped + "\""
+}
+
+fn json_state(state: &State) -> String {
+    format!(
+        "{{\"registers\":[{}],\"flags\":{}}}",
+        state
+            .registers
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        state.flags
+    )
+}
+
+impl Record<'_> {
+    fn write_text(&self, output: &mut dyn Write) -> io::Result<()> {
+        let registers = (0..=Register::SP.0 as usize)
+            .map(|index| {
+                format!(
+                    "{}={:02x}",
+                    RegisterIndex(index as u8),
+                    self.before.registers[index]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut changes = (0..16)
+            .filter(|&index| self.before.registers[index] != self.after.registers[index])
+            .map(|index| {
+                format!(
+                    "{}:{:02x}->{:02x}",
+                    RegisterIndex(index as u8),
+                    self.before.registers[index],
+                    self.after.registers[index]
+                )
+            })
+            .collect::<Vec<_>>();
+        if self.before.flags != self.after.flags {
+            changes.push(format!(
+                "flags:{:02x}->{:02x}",
+                self.before.flags, self.after.flags
+            ));
+        }
+        changes.extend(self.accesses.iter().map(|access| {
+            format!(
+                "{}[{:04x}]={:02x}",
+                access.kind, access.address, access.value
+            )
+        }));
+        if let Some(fault) = self.fault {
+            changes.push(format!("fault: {}", fault));
+        }
+        writeln!(
+            output,
+            "{:>8} {:02x}: {:<24} | {} flags={:02x} | {}",
+            self.step,
+            self.pc,
+            self.text,
+            registers,
+            self.before.flags,
+            changes.join(" ")
+        )
+    }
+
+    fn write_json(&self, output: &mut dyn Write) -> io::Result<()> {
+        let accesses = self
+            .accesses
+            .iter()
+            .map(|access| {
+                format!(
+                    "{{\"access\":\"{}\",\"address\":{},\"value\":{}}}",
+                    access.kind, access.address, access.value
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let fault = self
+            .fault
+            .map_or("null".to_string(), |fault| json_string(&fault.to_string()));
+        writeln!(
+            output,
+            "{{\"step\":{},\"pc\":{},\"instruction\":{},\"bytes\":\"{}\",\"before\":{},\"after\":{},\"memory\":[{}],\"fault\":{}}}",
+            self.step,
+            self.pc,
+            json_string(&self.text),
+            hex::encode(&self.bytes),
+            json_state(&self.before),
+            json_state(&self.after),
+            accesses,
+            fault
+        )
+    }
+}
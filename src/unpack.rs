@@ -0,0 +1,142 @@
+use std::fmt::{Display, Formatter};
+
+use crate::error::VmError;
+use crate::instruction::{Instruction, JumpFar, JumpIfNotEqual, MovReg8Const8};
+use crate::layout::{MemoryLayout, PcWidth};
+use crate::registers::{Register, RegisterIndex};
+use crate::vm::{WriteBitmap, VM};
+
+/*
+Unpacking of self-decoding images. The VM runs with a write bitmap until PC
+lands on a byte stored since load, the memory at that point is the decoded
+image. Flat images always start at PC 0, so the dump gets a trampoline there
+that restores the registers and jumps to the decoded code.
+ */
+
+/// Instructions executed before giving up on reaching decoded code
+pub const DEFAULT_UNPACK_STEPS: usize = 1 << 24;
+
+#[derive(Debug)]
+pub enum UnpackError {
+    Vm(VmError),
+    /// The program finished without running code it wrote
+    Exited,
+    StepLimit(usize),
+    /// The trampoline does not fit below the entry point
+    NoRoom {
+        entry: u16,
+        needed: usize,
+    },
+}
+
+impl Display for UnpackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnpackError::Vm(error) => write!(f, "{}", error),
+            UnpackError::Exited => write!(f, "program exited without running decoded code"),
+            UnpackError::StepLimit(steps) => {
+                write!(f, "no decoded code reached after {} instructions", steps)
+            }
+            UnpackError::NoRoom { entry, needed } => write!(
+                f,
+                "entry point {:02x} leaves no room for a {} byte trampoline",
+                entry, needed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UnpackError {}
+
+/// Steps until PC lands on a byte in `vm.write_bitmap`, returns false when `stop` or the
+/// program ends it first
+pub fn run_to_written_code(
+    vm: &mut VM,
+    max_steps: usize,
+    stop: impl Fn(&VM) -> bool,
+) -> Result<bool, VmError> {
+    for _ in 0..max_steps {
+        let pc = vm.pc();
+        if vm
+            .write_bitmap
+            .as_ref()
+            .is_some_and(|bitmap| bitmap.contains(pc))
+        {
+            return Ok(true);
+        }
+        if vm.stop || stop(vm) {
+            return Ok(false);
+        }
+        vm.step()?;
+    }
+    Ok(false)
+}
+
+pub struct Unpacked {
+    pub image: Vec<u8>,
+    pub entry: u16,
+    /// Flags were set at the entry point, the trampoline leaves them clear
+    pub flags_lost: bool,
+}
+
+pub fn unpack(
+    image: &[u8],
+    layout: &MemoryLayout,
+    max_steps: usize,
+) -> Result<Unpacked, UnpackError> {
+    let mut vm = VM::new(layout.clone());
+    vm.load(image).map_err(UnpackError::Vm)?;
+    vm.write_bitmap = Some(WriteBitmap::new(vm.memory.len()));
+    if !run_to_written_code(&mut vm, max_steps, |_| false).map_err(UnpackError::Vm)? {
+        return Err(if vm.stop {
+            UnpackError::Exited
+        } else {
+            UnpackError::StepLimit(max_steps)
+        });
+    }
+    let entry = vm.pc();
+    let pc_registers = match vm.layout.pc_width() {
+        PcWidth::Narrow => vec![Register::PC.0 as usize],
+        PcWidth::Wide => vec![Register::PC.0 as usize, Register::PCH.0 as usize],
+    };
+
+    // Flags start out clear, so the trailing jne is always taken
+    let mut trampoline: Vec<Box<dyn Instruction>> = vm
+        .registers
+        .registers
+        .iter()
+        .enumerate()
+        .filter(|&(index, register)| !pc_registers.contains(&index) && register.value != 0)
+        .map(|(index, register)| -> Box<dyn Instruction> {
+            Box::new(MovReg8Const8 {
+                to: RegisterIndex(index as u8),
+                value: register.value,
+            })
+        })
+        .collect();
+    let mut memory = vm.memory.to_vec();
+    if entry != 0 || !trampoline.is_empty() {
+        trampoline.push(match vm.layout.pc_width() {
+            PcWidth::Narrow => Box::new(JumpIfNotEqual {
+                address: entry as u8,
+            }),
+            PcWidth::Wide => Box::new(JumpFar { address: entry }),
+        });
+        let bytes = trampoline
+            .iter()
+            .flat_map(|instruction| instruction.encode())
+            .collect::<Vec<_>>();
+        if bytes.len() > entry as usize {
+            return Err(UnpackError::NoRoom {
+                entry,
+                needed: bytes.len(),
+            });
+        }
+        memory[..bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok(Unpacked {
+        image: memory,
+        entry,
+        flags_lost: vm.flags.into_bits() != 0,
+    })
+}
@@ -1,30 +1,184 @@
 use std::fmt::{Display, Formatter};
-use std::ops::Range;
+use std::time::Instant;
 
 use bitfield_struct::bitfield;
 
-use crate::instruction::Instruction;
+use crate::bus::{Bus, BusError, Device, RngDevice};
+use crate::console::{Console, Stdio};
+use crate::error::{Fault, VmError};
+use crate::history::{Change, History, LastWrite, Location};
+use crate::instruction::{Decoded, Instruction, MAX_INSTRUCTION_LEN};
+use crate::layout::{MemoryLayout, PcWidth, Region};
+use crate::limits::{Limits, Usage};
 use crate::registers::{Register, RegisterIndex, RegisterSet};
+use crate::syscall::SyscallTable;
 
 #[bitfield(u8)]
+#[derive(PartialEq, Eq)]
 pub struct Flags {
+    /// Result was zero, set by compares of equal values
     #[bits(1)]
-    pub equal: bool,
+    pub zero: bool,
 
-    #[bits(7)]
+    /// Unsigned carry out of an add, borrow out of a subtract
+    #[bits(1)]
+    pub carry: bool,
+
+    /// Bit 7 of the result
+    #[bits(1)]
+    pub sign: bool,
+
+    /// Signed overflow
+    #[bits(1)]
+    pub overflow: bool,
+
+    #[bits(4)]
     __: usize,
 }
 
-/*
-[0, 0x100) => instructions
-[0x100, 0x300) => memory
-[0x300, 0x400) => stack
- */
+impl Flags {
+    fn of(result: u8, carry: bool, overflow: bool) -> Self {
+        Flags::new()
+            .with_zero(result == 0)
+            .with_carry(carry)
+            .with_sign(result & 0x80 != 0)
+            .with_overflow(overflow)
+    }
+
+    /// Wrapping `left + right` and the flags it sets
+    pub fn add(left: u8, right: u8) -> (u8, Flags) {
+        let (result, carry) = left.overflowing_add(right);
+        let (_, overflow) = (left as i8).overflowing_add(right as i8);
+        (result, Flags::of(result, carry, overflow))
+    }
+
+    /// Wrapping `left - right` and the flags it sets, carry is the borrow
+    pub fn sub(left: u8, right: u8) -> (u8, Flags) {
+        let (result, borrow) = left.overflowing_sub(right);
+        let (_, overflow) = (left as i8).overflowing_sub(right as i8);
+        (result, Flags::of(result, borrow, overflow))
+    }
+}
+
 pub struct VM {
-    pub memory: [u8; VM::VM_BOUNDARY],
+    pub memory: Vec<u8>,
+    pub layout: MemoryLayout,
     pub registers: RegisterSet,
     pub flags: Flags,
     pub stop: bool,
+    /// Exit status, set by the exit syscall
+    pub status: u8,
+    /// Where `read` and `write` go, stdin and stdout unless replaced
+    pub console: Box<dyn Console>,
+    /// Devices mapped outside the layout, see VM::attach
+    pub bus: Bus,
+    /// Handlers `syscall` dispatches to, the built-in ones unless replaced
+    pub syscalls: SyscallTable,
+    /// Generator behind the random syscall, seeded by the host and saved in snapshots
+    pub rng: RngDevice,
+    pub limits: Limits,
+    pub usage: Usage,
+    /// Loads and stores made by instructions, recorded only while Some
+    pub access_log: Option<Vec<MemoryAccess>>,
+    /// Addresses stored to by instructions, recorded only while Some
+    pub write_bitmap: Option<WriteBitmap>,
+    /// Undo logs of the steps taken, recorded only while Some
+    pub history: Option<History>,
+    /// Instructions VM::step decoded before, code that changes `memory` without VM::write
+    /// must invalidate it
+    pub decode_cache: DecodeCache,
+}
+
+/// Decoded instructions by address, one slot per byte of VM::memory
+#[derive(Clone)]
+pub struct DecodeCache {
+    slots: Vec<Option<Decoded>>,
+}
+
+impl DecodeCache {
+    pub fn new(len: usize) -> Self {
+        Self {
+            slots: vec![None; len],
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<Decoded> {
+        self.slots.get(address as usize).copied().flatten()
+    }
+
+    pub fn insert(&mut self, address: u16, decoded: Decoded) {
+        if let Some(slot) = self.slots.get_mut(address as usize) {
+            *slot = Some(decoded);
+        }
+    }
+
+    /// Drops every cached instruction whose encoding covers `address`
+    pub fn invalidate(&mut self, address: u16) {
+        let start = (address as usize).saturating_sub(MAX_INSTRUCTION_LEN - 1);
+        let end = (address as usize + 1).min(self.slots.len());
+        if start < end {
+            self.slots[start..end].fill(None);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.fill(None);
+    }
+}
+
+/// One bit per byte of VM::memory
+#[derive(Clone)]
+pub struct WriteBitmap {
+    words: Vec<u64>,
+}
+
+impl WriteBitmap {
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    pub fn set(&mut self, address: u16) {
+        self.words[address as usize / 64] |= 1 << (address % 64);
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        self.words
+            .get(address as usize / 64)
+            .is_some_and(|word| word & 1 << (address % 64) != 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.words.len() * 64)
+            .map(|address| address as u16)
+            .filter(|&address| self.contains(address))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// Instruction fetch, never recorded in VM::access_log
+    Execute,
+}
+
+impl Display for AccessKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessKind::Read => write!(f, "read"),
+            AccessKind::Write => write!(f, "write"),
+            AccessKind::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u8,
 }
 
 #[derive(Clone, Copy)]
@@ -33,11 +187,11 @@ pub struct Address16 {
     pub low: u8,
 }
 
-impl Into<u16> for Address16 {
-    fn into(self) -> u16 {
-        let mut address = self.high as u16;
+impl From<Address16> for u16 {
+    fn from(value: Address16) -> Self {
+        let mut address = value.high as u16;
         address <<= 8;
-        address |= self.low as u16;
+        address |= value.low as u16;
         address
     }
 }
@@ -58,48 +212,392 @@ pub struct AddressReg16 {
 }
 
 impl AddressReg16 {
-    pub fn eval_vm(&self, vm: &VM) -> u16 {
-        let mut address = vm.registers[self.high].value as u16;
+    pub fn eval_vm(&self, vm: &VM) -> Result<u16, Fault> {
+        let mut address = vm.registers.get(self.high)?.value as u16;
         address <<= 8;
-        address |= vm.registers[self.low].value as u16;
-        address
+        address |= vm.registers.get(self.low)?.value as u16;
+        Ok(address)
+    }
+}
+
+impl VM {
+    pub fn new(layout: MemoryLayout) -> Self {
+        let size = layout.size();
+        Self {
+            memory: vec![0; size],
+            layout,
+            registers: RegisterSet::new(),
+            flags: Flags::new(),
+            stop: false,
+            status: 0,
+            console: Box::new(Stdio::new()),
+            bus: Bus::default(),
+            syscalls: SyscallTable::builtin(),
+            rng: RngDevice::new(0),
+            limits: Limits::default(),
+            usage: Usage::default(),
+            access_log: None,
+            write_bitmap: None,
+            history: None,
+            decode_cache: DecodeCache::new(size),
+        }
+    }
+
+    /// Loads a flat image, which covers every region of the layout
+    pub fn load(&mut self, stream: &[u8]) -> Result<(), VmError> {
+        if stream.len() != self.layout.size() {
+            return Err(VmError::InvalidImageSize {
+                expected: self.layout.size(),
+                actual: stream.len(),
+            });
+        }
+        self.memory.copy_from_slice(stream);
+        self.decode_cache.clear();
+        Ok(())
+    }
+
+    pub fn pc(&self) -> u16 {
+        let low = self.registers[Register::PC].value as u16;
+        match self.layout.pc_width() {
+            PcWidth::Narrow => low,
+            PcWidth::Wide => (self.registers[Register::PCH].value as u16) << 8 | low,
+        }
+    }
+
+    /// Callers keep `pc` within PcWidth::max_pc, VM::jump checks it
+    pub fn set_pc(&mut self, pc: u16) {
+        self.registers[Register::PC].value = pc as u8;
+        if self.layout.pc_width() == PcWidth::Wide {
+            self.registers[Register::PCH].value = (pc >> 8) as u8;
+        }
+    }
+
+    /// Moves PC to an absolute target
+    pub fn jump(&mut self, target: u16) -> Result<(), Fault> {
+        if target > self.layout.pc_width().max_pc() {
+            return Err(Fault::AddressOutOfBounds(target));
+        }
+        self.set_pc(target);
+        Ok(())
+    }
+
+    /// Moves PC to an 8-bit target in the 256-byte page PC is in, which in narrow
+    /// images is the whole instruction region
+    pub fn jump_near(&mut self, target: u8) {
+        self.set_pc(self.pc() & 0xff00 | target as u16);
+    }
+
+    pub fn run(&mut self, stream: &[u8]) -> Result<(), VmError> {
+        self.load(stream)?;
+        while !self.stop {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Maps `device` at `start`, its range must not overlap the layout or another device
+    pub fn attach(&mut self, start: u16, device: Box<dyn Device>) -> Result<(), BusError> {
+        self.bus.attach(start, device, 0..self.layout.size())
+    }
+
+    /// Program input on behalf of an instruction or device, one read from the console
+    pub fn input(&mut self, buffer: &mut [u8]) -> Result<usize, Fault> {
+        let len = self
+            .limits
+            .input_budget(&self.usage, buffer.len())
+            .map_err(Fault::LimitExceeded)?;
+        // Never read past the budget, the rest of the buffer is left as it is
+        let buffer = &mut buffer[..len];
+        let replayed = self
+            .history
+            .as_mut()
+            .map_or(0, |history| history.replay(buffer));
+        let read = if replayed > 0 {
+            replayed
+        } else {
+            self.console
+                .read(buffer)
+                .map_err(|error| Fault::Io(error.kind()))?
+        };
+        if let Some(history) = &mut self.history {
+            history.record_input(&buffer[..read]);
+        }
+        self.usage.input += read as u64;
+        Ok(read)
+    }
+
+    /// Next byte from VM::rng on behalf of the random syscall
+    pub fn random_byte(&mut self) -> u8 {
+        if let Some(history) = &mut self.history {
+            history.record(Change::Rng(self.rng.save()));
+        }
+        self.rng.next_byte()
+    }
+
+    /// Program output on behalf of an instruction or device
+    pub fn output(&mut self, bytes: &[u8]) -> Result<(), Fault> {
+        self.limits
+            .check_output(&self.usage, bytes.len())
+            .map_err(Fault::LimitExceeded)?;
+        self.console
+            .write(bytes)
+            .map_err(|error| Fault::Io(error.kind()))?;
+        if let Some(history) = &mut self.history {
+            history.record_output(bytes.len());
+        }
+        self.usage.output += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Runs `access` on the device claiming `address`, None when memory holds it. The bus
+    /// is out of the VM meanwhile, so the device can use the rest of it
+    fn device_access<T>(
+        &mut self,
+        address: u16,
+        access: impl FnOnce(&mut dyn Device, u16, &mut VM) -> Result<T, Fault>,
+    ) -> Option<Result<T, Fault>> {
+        let mut bus = std::mem::take(&mut self.bus);
+        let result = bus.device_at(address).map(|(offset, device)| {
+            if let Some(history) = &mut self.history {
+                let start = address - offset;
+                if !history.saved_device(start) {
+                    history.record(Change::Device {
+                        start,
+                        state: device.save(),
+                    });
+                }
+            }
+            access(device, offset, self)
+        });
+        self.bus = bus;
+        result
+    }
+
+    /// Memory load on behalf of an instruction, addresses claimed by a device go to it
+    pub fn read(&mut self, address: u16) -> Result<u8, Fault> {
+        let device = self.device_access(address, |device, offset, vm| device.read(offset, vm));
+        let value = match device {
+            Some(value) => value?,
+            None => {
+                self.layout.check(address, AccessKind::Read)?;
+                self.memory[address as usize]
+            }
+        };
+        self.log(AccessKind::Read, address, value);
+        Ok(value)
+    }
+
+    /// Memory store on behalf of an instruction, addresses claimed by a device go to it
+    pub fn write(&mut self, address: u16, value: u8) -> Result<(), Fault> {
+        let device = self.device_access(address, |device, offset, vm| {
+            device.write(offset, value, vm)
+        });
+        match device {
+            Some(result) => result?,
+            None => {
+                self.layout.check(address, AccessKind::Write)?;
+                if let Some(history) = &mut self.history {
+                    history.record(Change::Memory {
+                        address,
+                        old: self.memory[address as usize],
+                    });
+                }
+                self.memory[address as usize] = value;
+                self.decode_cache.invalidate(address);
+                if let Some(bitmap) = &mut self.write_bitmap {
+                    bitmap.set(address);
+                }
+            }
+        }
+        self.log(AccessKind::Write, address, value);
+        Ok(())
+    }
+
+    /// Stores at the top of the stack region, which grows upwards from SP 0
+    pub fn push(&mut self, value: u8) -> Result<(), Fault> {
+        let sp = self.registers[Register::SP].value;
+        let top = sp.checked_add(1).ok_or(Fault::StackFull)?;
+        self.write((self.layout.stack().start + sp as usize) as u16, value)?;
+        self.registers[Register::SP].value = top;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<u8, Fault> {
+        let sp = self.registers[Register::SP].value;
+        let top = sp.checked_sub(1).ok_or(Fault::StackEmpty)?;
+        let value = self.read((self.layout.stack().start + top as usize) as u16)?;
+        self.registers[Register::SP].value = top;
+        Ok(value)
+    }
+
+    fn log(&mut self, kind: AccessKind, address: u16, value: u8) {
+        if let Some(log) = &mut self.access_log {
+            log.push(MemoryAccess {
+                kind,
+                address,
+                value,
+            });
+        }
+    }
+
+    /// Decodes the instruction at PC without executing it, the instruction must fit in
+    /// the executable region PC is in
+    pub fn fetch(&self) -> Result<Box<dyn Instruction>, VmError> {
+        self.decode().map(Decoded::into_boxed)
+    }
+
+    fn decode(&self) -> Result<Decoded, VmError> {
+        let pc = self.pc();
+        let fault = |fault| VmError::Fault {
+            pc,
+            bytes: vec![],
+            fault,
+        };
+        self.layout.check(pc, AccessKind::Execute).map_err(fault)?;
+        let region = self.layout.region_at(pc as usize).expect("checked above");
+        let instructions = &self.memory[pc as usize..region.range().end];
+        Decoded::parse(instructions).map_err(|error| VmError::decode(pc, instructions, error))
+    }
+
+    /// Like fetch, from the decode cache when the instruction at PC was decoded before
+    fn fetch_cached(&mut self) -> Result<Decoded, VmError> {
+        let pc = self.pc();
+        if let Some(decoded) = self.decode_cache.get(pc) {
+            // Permissions may have changed since
+            self.layout
+                .check(pc, AccessKind::Execute)
+                .map_err(|fault| VmError::Fault {
+                    pc,
+                    bytes: vec![],
+                    fault,
+                })?;
+            return Ok(decoded);
+        }
+        let decoded = self.decode()?;
+        self.decode_cache.insert(pc, decoded);
+        Ok(decoded)
+    }
+
+    /// Fetches, decodes and executes a single instruction
+    pub fn step(&mut self) -> Result<(), VmError> {
+        let pc = self.pc();
+        let instruction = self.fetch_cached()?;
+        let fault = |fault| VmError::Fault {
+            pc,
+            bytes: instruction.encode(),
+            fault,
+        };
+        self.usage.started.get_or_insert_with(Instant::now);
+        self.limits
+            .check_step(&self.usage)
+            .map_err(|limit| fault(Fault::LimitExceeded(limit)))?;
+        let before = (self.registers.registers, self.flags, self.stop, self.status);
+        if let Some(history) = &mut self.history {
+            history.begin(pc);
+        }
+        let end = pc as usize + instruction.len() as usize;
+        let overflow = end > self.layout.pc_width().max_pc() as usize;
+        let next = (end & self.layout.pc_width().max_pc() as usize) as u16;
+        self.set_pc(next);
+        let result = instruction.execute(self);
+        if result.is_err() {
+            // Leave PC on the faulting instruction
+            self.set_pc(pc);
+        }
+        self.commit_step(before, result.is_ok());
+        result.map_err(fault)?;
+        self.usage.instructions += 1;
+        // Falling through past the last byte of the address space is a fault,
+        // jumping away from an instruction that ends there is not
+        if !self.stop && overflow && self.pc() == next {
+            return Err(fault(Fault::ProgramCounterOverflow));
+        }
+        Ok(())
     }
 }
 
 impl VM {
-    pub const INSTRUCTIONS_BOUNDARY: usize = 0x100;
-    pub const MEMORY_BOUNDARY: usize = 0x300;
-    pub const STACK_BOUNDARY: usize = 0x400;
-
-    pub const VM_BOUNDARY: usize = VM::STACK_BOUNDARY;
-
-    pub const INSTRUCTIONS_RANGE: Range<usize> = 0..Self::INSTRUCTIONS_BOUNDARY;
-    pub const MEMORY_RANGE: Range<usize> = Self::INSTRUCTIONS_BOUNDARY..Self::MEMORY_BOUNDARY;
-    pub const STACK_RANGE: Range<usize> = Self::MEMORY_BOUNDARY..Self::STACK_BOUNDARY;
-
-    pub fn run(&mut self, stream: &Vec<u8>) {
-        assert_eq!(stream.len(), Self::VM_BOUNDARY, "Invalid address space");
-        self.memory.copy_from_slice(stream.as_slice());
-        loop {
-            let instructions = &self.memory[VM::INSTRUCTIONS_RANGE]
-                [(self.registers[Register::PC].value as usize)..];
-            let mut iter = instructions.iter();
-            let mut next = || match iter.next() {
-                Some(value) => *value,
-                None => panic!("Could not get instruction parameter"),
-            };
-            let instruction = <dyn Instruction>::parse(&mut next);
-            let count = instruction.len();
-            self.registers[Register::PC].value += count as u8;
-            self.step(&instruction);
-            if self.stop {
-                break;
+    /// Records the registers, flags and exit state the step changed and closes its undo log
+    fn commit_step(&mut self, before: ([Register; 16], Flags, bool, u8), completed: bool) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        let (registers, flags, stop, status) = before;
+        for (index, (old, new)) in registers.iter().zip(&self.registers.registers).enumerate() {
+            if old.value != new.value {
+                history.record(Change::Register {
+                    index: index as u8,
+                    old: old.value,
+                });
+            }
+        }
+        if flags != self.flags {
+            history.record(Change::Flags(flags.into_bits()));
+        }
+        if (stop, status) != (self.stop, self.status) {
+            history.record(Change::Exit { stop, status });
+        }
+        history.commit(completed);
+    }
+
+    /// Undoes the latest recorded step, false when there is none
+    pub fn reverse_step(&mut self) -> bool {
+        let Some(step) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        for change in step.changes.into_iter().rev() {
+            match change {
+                Change::Register { index, old } => {
+                    self.registers.registers[index as usize].value = old
+                }
+                Change::Memory { address, old } => {
+                    self.memory[address as usize] = old;
+                    self.decode_cache.invalidate(address);
+                }
+                Change::Flags(old) => self.flags = Flags::from_bits(old),
+                Change::Exit { stop, status } => {
+                    self.stop = stop;
+                    self.status = status;
+                }
+                Change::Device { start, state } => {
+                    if let Some((_, device)) = self.bus.devices_mut().find(|(at, _)| *at == start) {
+                        device.restore(&state);
+                    }
+                }
+                Change::Rng(state) => self.rng.restore(&state),
             }
         }
+        self.set_pc(step.pc);
+        self.usage.instructions -= step.completed as u64;
+        self.usage.input -= step.input.len() as u64;
+        self.usage.output -= step.output;
+        true
     }
 
-    pub fn step(&mut self, instruction: &Box<dyn Instruction>) {
-        instruction.execute(self);
+    /// Undoes steps until `until` holds, at least one. Returns false when the history ran
+    /// out first
+    pub fn reverse_until(&mut self, until: impl Fn(&VM) -> bool) -> bool {
+        while self.reverse_step() {
+            if until(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The latest recorded write to a register or memory byte
+    pub fn last_write(&self, location: Location) -> Option<LastWrite> {
+        let current = match location {
+            Location::Register(index) => self.registers.get(index).ok()?.value,
+            Location::Memory(address) => *self.memory.get(address as usize)?,
+        };
+        self.history.as_ref()?.last_write(location, current)
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new(MemoryLayout::default())
     }
 }
 
@@ -109,21 +607,18 @@ impl Display for VM {
         for (index, register) in self.registers.registers.iter().enumerate() {
             registers += &format!("Register[{}] = {:02x}\n", index, register.value).to_string();
         }
-        let dump = |name: &str, range: Range<usize>| {
-            name.to_string()
+        let dump = |region: &Region| {
+            region.name.clone()
                 + "\n"
-                + &*hexdump::hexdump_iter(&self.memory[range])
+                + &*hexdump::hexdump_iter(&self.memory[region.range()])
                     .map(|line| format!("{}", line))
                     .collect::<Vec<_>>()
                     .join("\n")
         };
-        let full = [
-            registers,
-            dump("Instructions", Self::INSTRUCTIONS_RANGE),
-            dump("Memory", Self::MEMORY_RANGE),
-            dump("Stack", Self::STACK_RANGE),
-        ]
-        .join("\n");
+        let full = std::iter::once(registers)
+            .chain(self.layout.regions().iter().map(dump))
+            .collect::<Vec<_>>()
+            .join("\n");
         write!(f, "{}", full)
     }
 }
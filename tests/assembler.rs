@@ -0,0 +1,54 @@
+use x8::assembler::{assemble, AsmError};
+
+fn error(source: &str) -> AsmError {
+    assemble(source).expect_err("source should not assemble")
+}
+
+#[test]
+fn labels_resolve_before_and_after_their_definition() {
+    let image = assemble(
+        "    .equ LEN, 3
+    .text
+start:
+    mov R0, LEN
+    jne start
+    jmp end
+end:
+    exit
+",
+    )
+    .unwrap();
+    assert_eq!(image.len(), 0x400);
+    assert_eq!(image[..8], [0x01, 0x00, 0x03, 0x04, 0x00, 0x0e, 0x07, 0x00]);
+}
+
+#[test]
+fn data_and_stack_sections_land_in_their_regions() {
+    let image = assemble(
+        "    .text
+    exit
+    .data
+msg:
+    .db \"hi\", 0x41
+    .zero 2
+    .db 'B'
+    .stack
+    .db 7
+",
+    )
+    .unwrap();
+    assert_eq!(image[0x100..0x106], *b"hiA\0\0B");
+    assert_eq!(image[0x300], 7);
+    assert!(image[0x106..0x300].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn errors_report_line_and_column() {
+    let undefined = error("    .text\n    mov R0, 1\n    jne nowhere\n");
+    assert_eq!((undefined.line, undefined.column), (3, 9));
+    assert_eq!(undefined.message, "undefined symbol 'nowhere'");
+
+    let overflow = error("    mov R0, 0x7fffffffffffffff + 1\n");
+    assert_eq!((overflow.line, overflow.column), (1, 32));
+    assert_eq!(overflow.to_string(), "1:32: expression overflows 64 bits");
+}
@@ -0,0 +1,45 @@
+use x8::layout::MemoryLayout;
+use x8::{assembler, disassembler};
+
+fn listing(image: &[u8]) -> String {
+    disassembler::disassemble(image, &MemoryLayout::narrow()).unwrap()
+}
+
+fn reassemble(listing: &str) -> Vec<u8> {
+    assembler::assemble(listing).unwrap_or_else(|error| panic!("{}\n{}", error, listing))
+}
+
+#[test]
+fn bytes_that_do_not_decode_fall_back_to_db() {
+    let mut image = vec![0; 0x400];
+    // mov R0, 3; an unknown opcode; jne 0; and a `mov` cut off by the end of text
+    image[..7].copy_from_slice(&[0x01, 0x00, 0x03, 0xff, 0x04, 0x00, 0x00]);
+    image[0xff] = 0x01;
+    let listing = listing(&image);
+    assert!(listing.contains("    .db 0xff "));
+    assert!(listing.contains("    .db 0x01 "));
+    assert_eq!(reassemble(&listing), image);
+}
+
+#[test]
+fn labels_and_data_regions_reassemble_to_the_same_image() {
+    let source = "    .text
+start:
+    mov R0, 3
+    subi R0, 1
+    cmpi R0, 0
+    jne start
+    exit
+    .data
+    .db \"hello\", 0x0a
+    .zero 32
+    .db 0x7f
+    .stack
+    .db 1, 2, 3
+";
+    let image = reassemble(source);
+    let listing = listing(&image);
+    assert!(listing.contains("L_00:"));
+    assert!(listing.contains("jne L_00"));
+    assert_eq!(reassemble(&listing), image);
+}
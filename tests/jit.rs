@@ -0,0 +1,179 @@
+#![cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+
+use x8::assembler;
+use x8::error::{Fault, VmError};
+use x8::jit::{Jit, JitError};
+use x8::layout::MemoryLayout;
+use x8::registers::{Register, RegisterIndex};
+use x8::vm::VM;
+
+const CONDITIONS: [&str; 10] = ["e", "ne", "l", "g", "le", "ge", "b", "a", "be", "ae"];
+
+fn vm(source: &str) -> VM {
+    let image = assembler::assemble(source).unwrap_or_else(|error| panic!("{}", error));
+    let mut vm = VM::new(MemoryLayout::narrow());
+    vm.load(&image).unwrap();
+    vm
+}
+
+/// Runs `source` with every compiled block checked against the interpreter, and
+/// again with the interpreter alone
+fn run_checked(source: &str) -> (VM, Result<(), JitError>, VM, Result<(), VmError>) {
+    let mut jitted = vm(source);
+    let mut jit = Jit::new(&jitted.layout).unwrap();
+    jit.check = true;
+    let result = jit.run(&mut jitted);
+    assert!(jit.stats.native_instructions > 0, "{}", jit.stats);
+    let mut interpreted = vm(source);
+    let mut expected = Ok(());
+    while !interpreted.stop && expected.is_ok() {
+        expected = interpreted.step();
+    }
+    (jitted, result, interpreted, expected)
+}
+
+fn assert_same(jitted: &VM, interpreted: &VM) {
+    for index in 0..16 {
+        let register = RegisterIndex(index);
+        assert_eq!(
+            *jitted.registers[register], *interpreted.registers[register],
+            "{}",
+            register
+        );
+    }
+    assert_eq!(jitted.flags, interpreted.flags);
+    assert_eq!(jitted.memory, interpreted.memory);
+}
+
+/// Takes `jump` after cmp, cmpi, addi and subi over a spread of signed and unsigned
+/// operands, counting in R3 how often it falls through
+fn branch_program(jump: &str) -> String {
+    let mut source = "
+    .text
+    mov R0, 0
+    mov R5, 8
+outer:
+    mov R1, 0
+    mov R4, 8
+inner:
+    cmp R0, R1
+    JUMP after_cmp
+    addi R3, 1
+after_cmp:
+    cmpi R0, 0x80
+    JUMP after_cmpi
+    addi R3, 1
+after_cmpi:
+"
+    .to_string();
+    for (index, operation) in [
+        "addi R2, 0x7f",
+        "addi R2, 0x80",
+        "subi R2, 0x7f",
+        "subi R2, 0x01",
+    ]
+    .iter()
+    .enumerate()
+    {
+        source += &format!(
+            "    xor R2, R2
+    xor R2, R0
+    {}
+    JUMP op{}
+    addi R3, 1
+op{}:
+",
+            operation, index, index
+        );
+    }
+    source += "    addi R1, 0x25
+    subi R4, 1
+    jne inner
+    addi R0, 0x31
+    subi R5, 1
+    jne outer
+    exit
+";
+    source.replace("JUMP", jump)
+}
+
+#[test]
+fn flags_and_every_near_branch_condition_match_the_interpreter() {
+    for condition in CONDITIONS {
+        let (jitted, result, interpreted, expected) =
+            run_checked(&branch_program(&format!("j{}", condition)));
+        result.unwrap_or_else(|error| panic!("j{}: {}", condition, error));
+        expected.unwrap();
+        assert_same(&jitted, &interpreted);
+    }
+}
+
+#[test]
+fn every_far_branch_condition_matches_the_interpreter() {
+    for condition in CONDITIONS {
+        let (jitted, result, interpreted, expected) =
+            run_checked(&branch_program(&format!("lj{}", condition)));
+        result.unwrap_or_else(|error| panic!("lj{}: {}", condition, error));
+        expected.unwrap();
+        assert_same(&jitted, &interpreted);
+    }
+}
+
+#[test]
+fn push_on_a_full_stack_leaves_the_block_for_the_interpreter() {
+    let (jitted, result, interpreted, expected) = run_checked(
+        "
+    .text
+fill:
+    pushi 0x5a
+    push R0
+    addi R0, 1
+    jmp fill
+",
+    );
+    assert!(
+        matches!(
+            &expected,
+            Err(VmError::Fault {
+                fault: Fault::StackFull,
+                ..
+            })
+        ),
+        "{:?}",
+        expected
+    );
+    assert_eq!(result, expected.map_err(JitError::Vm));
+    assert_eq!(*jitted.registers[Register::SP], 0xff);
+    assert_same(&jitted, &interpreted);
+}
+
+#[test]
+fn pop_on_an_empty_stack_leaves_the_block_for_the_interpreter() {
+    let (jitted, result, interpreted, expected) = run_checked(
+        "
+    .text
+    pushi 3
+    pushi 4
+    push R7
+drain:
+    pop R1
+    addi R2, 1
+    jmp drain
+",
+    );
+    assert!(
+        matches!(
+            &expected,
+            Err(VmError::Fault {
+                fault: Fault::StackEmpty,
+                ..
+            })
+        ),
+        "{:?}",
+        expected
+    );
+    assert_eq!(result, expected.map_err(JitError::Vm));
+    assert_eq!(*jitted.registers[Register::R1], 3);
+    assert_eq!(*jitted.registers[Register::R2], 3);
+    assert_same(&jitted, &interpreted);
+}
@@ -0,0 +1,11 @@
+use x8::layout::MemoryLayout;
+use x8::{assembler, disassembler};
+
+#[test]
+fn program_bin_reassembles_to_the_same_image() {
+    let image = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/program.bin")).unwrap();
+    let layout = MemoryLayout::for_image_len(image.len()).unwrap();
+    let listing = disassembler::disassemble(&image, &layout).unwrap();
+    let reassembled = assembler::assemble(&listing).unwrap_or_else(|error| panic!("{}", error));
+    assert_eq!(reassembled, image);
+}
@@ -0,0 +1,32 @@
+use x8::assembler;
+use x8::layout::{MemoryLayout, Permissions, RegionKind};
+use x8::registers::Register;
+use x8::vm::VM;
+
+/// Rewrites the immediate of an instruction that already ran and is in the decode
+/// cache, like the XorMemReg8Const8 decoder in program.bin
+const SELF_MODIFYING: &str = "
+    .text
+    mov R3, patch+2
+    mov R0, 0
+loop:
+patch:
+    addi R1, 1
+    xorm [R3], 0x03
+    addi R0, 1
+    cmpi R0, 2
+    jne loop
+    exit
+";
+
+#[test]
+fn rewritten_code_runs_instead_of_its_cached_decode() {
+    let image = assembler::assemble(SELF_MODIFYING).unwrap();
+    let mut layout = MemoryLayout::narrow();
+    layout.set_permissions(RegionKind::Instructions, Permissions::RWX);
+    let mut vm = VM::new(layout);
+    vm.run(&image).unwrap();
+    // addi R1, 1 on the first pass and addi R1, 2 once patched
+    assert_eq!(*vm.registers[Register::R1], 3);
+    assert_eq!(*vm.registers[Register::R0], 2);
+}